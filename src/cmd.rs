@@ -1,80 +1,252 @@
 use log::{debug, trace};
 
-const GENERATE_BY_WORD_COMMAND: &str = "/q";
-const DISABLE_FOR_CHAT_COMMAND: &str = "/off";
-const ENABLE_FOR_CHAT_COMMAND: &str = "/on";
-const GET_WORD_COUNT_COMMAND: &str = "/count";
-const START_BOT_COMMAND: &str = "/start";
-const CHANGE_LEXEME_TABLE_COMMAND: &str = "/changetable";
-const GET_CURRENT_LEXEME_TABLE_COMMAND: &str = "/getcurrenttable";
-const GET_LEXEME_TABLE_LIST_COMMAND: &str = "/listtable";
-const HELP_COMMAND: &str = "/help";
-const ADMIN_HELP_COMMAND: &str = "/adminhelp";
-
-pub struct CommandParser;
-
-pub enum CommandType {
-    ENoCommand,
-    EGenerateByWord(String),
-    EGetCountByWord(String),
-    EDisableForChat,
-    EEnableForChat,
-    EChangeLexemeTable(String),
-    EGetLexemeTable,
-    EListLexemeTables,
-    ENewUser,
-    EHelpCommand,
-    EAdminHelpCommand,
+// Every command of the bot is described by a `Command` entry of a registry,
+// parsing, permission checks, usage replies, help and the telegram menu are
+// all built from these entries. `C` is whatever the handlers work with.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Everyone,
+    Admin,
 }
 
-impl CommandParser {
-    pub fn parse_command(input: &String) -> CommandType {
-        let tokens: Vec<&str> = input.trim().split_whitespace().collect();
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    Required(&'static str),
+    Optional(&'static str),
+    // any number of words, only as the last argument
+    Rest(&'static str),
+}
 
-        if tokens.is_empty() {
-            debug!("tokens vec is empty");
-            return CommandType::ENoCommand
+pub struct Command<C: 'static, R: 'static> {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    // required arguments go before optional ones, a rest one is the last
+    pub args: &'static [Arg],
+    pub permission: Permission,
+    pub description: &'static str,
+    pub handler: fn(&mut C, &[String]) -> R,
+}
+
+impl<C, R> Command<C, R> {
+    fn is_called(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+
+    fn accepts(&self, args: &[String]) -> bool {
+        let required = self.args.iter().filter(|arg| matches!(arg, Arg::Required(_))).count();
+        let unlimited = matches!(self.args.last(), Some(Arg::Rest(_)));
+        args.len() >= required && (unlimited || args.len() <= self.args.len())
+    }
+
+    pub fn usage(&self) -> String {
+        let mut usage = String::from(self.name);
+        for arg in self.args {
+            match arg {
+                Arg::Required(name) => usage.push_str(&format!(" <{}>", name)),
+                Arg::Optional(name) => usage.push_str(&format!(" [{}]", name)),
+                Arg::Rest(name) => usage.push_str(&format!(" [{}...]", name)),
+            }
         }
+        usage
+    }
+}
 
-        let command: Vec<&str> = tokens[0].split('@').collect();
+pub enum ParsedCommand<C: 'static, R: 'static> {
+    // plain text or a command nobody registered
+    ENoCommand,
+    // `/cmd@OtherBot`, neither answered nor learned
+    EForeignCommand,
+    EWrongUsage(&'static Command<C, R>),
+    ECommand(&'static Command<C, R>, Vec<String>),
+}
 
-        // TODO: check if command[1] == 'JelezyakaBot' or test bot or empty 
+// Splits the input by whitespace, except for parts in double quotes which
+// become a single token, an unterminated quote lasts to the end of the input
+pub fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut has_token = false;
 
-        match command[0] {
-            START_BOT_COMMAND => {
-                trace!("New user started bot");
-                CommandType::ENewUser
+    for c in input.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_token = true;
             },
-            GENERATE_BY_WORD_COMMAND => {
-                if tokens.len() == 2 {
-                    trace!("Gen by word");
-                    CommandType::EGenerateByWord(String::from(tokens[1]))
-                } else {
-                    trace!("Gen by word: nocmd here");
-                    CommandType::ENoCommand
+            c if c.is_whitespace() && !quoted => {
+                if has_token {
+                    tokens.push(token.clone());
+                    token.clear();
+                    has_token = false;
                 }
             },
-            GET_WORD_COUNT_COMMAND => {
-                if tokens.len() == 2 {
-                    CommandType::EGetCountByWord(String::from(tokens[1]))
-                } else {
-                    CommandType::ENoCommand
-                }
+            c => {
+                token.push(c);
+                has_token = true;
             },
-            CHANGE_LEXEME_TABLE_COMMAND => {
-                if tokens.len() == 2 {
-                    CommandType::EChangeLexemeTable(String::from(tokens[1]))
-                } else {
-                    CommandType::ENoCommand
-                }
+        }
+    }
+
+    if has_token {
+        tokens.push(token);
+    }
+    tokens
+}
+
+// Knows the bot's own username, so in chats with several bots it picks
+// only commands which are addressed to us or to nobody in particular
+pub struct CommandParser<C: 'static, R: 'static> {
+    username: Option<String>,
+    commands: &'static [Command<C, R>],
+}
+
+impl<C, R> CommandParser<C, R> {
+    pub fn new(username: Option<String>, commands: &'static [Command<C, R>]) -> CommandParser<C, R> {
+        debug!("Command parser for bot {:?} with {} commands", username, commands.len());
+        CommandParser { username, commands }
+    }
+
+    fn is_addressed_to_us(&self, target: Option<&str>) -> bool {
+        match (target, &self.username) {
+            (None, _) => true,
+            (Some(target), Some(username)) => target.eq_ignore_ascii_case(username),
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn parse_command(&self, input: &str) -> ParsedCommand<C, R> {
+        if !input.trim_start().starts_with('/') {
+            return ParsedCommand::ENoCommand
+        }
+
+        let mut tokens = tokenize(input);
+
+        if tokens.is_empty() {
+            debug!("tokens vec is empty");
+            return ParsedCommand::ENoCommand
+        }
+
+        let args = tokens.split_off(1);
+        let mut command = tokens[0].splitn(2, '@');
+        let name = command.next().unwrap_or_default();
+        let target = command.next();
+
+        if !self.is_addressed_to_us(target) {
+            trace!("Command {} is for another bot {:?}", name, target);
+            return ParsedCommand::EForeignCommand
+        }
+
+        match self.commands.iter().find(|command| command.is_called(name)) {
+            Some(command) if command.accepts(&args) => {
+                trace!("Command {} with {:?}", command.name, args);
+                ParsedCommand::ECommand(command, args)
             },
-            DISABLE_FOR_CHAT_COMMAND => CommandType::EDisableForChat,
-            ENABLE_FOR_CHAT_COMMAND => CommandType::EEnableForChat,
-            GET_CURRENT_LEXEME_TABLE_COMMAND => CommandType::EGetLexemeTable,
-            HELP_COMMAND => CommandType::EHelpCommand,
-            ADMIN_HELP_COMMAND => CommandType::EAdminHelpCommand,
-            GET_LEXEME_TABLE_LIST_COMMAND => CommandType::EListLexemeTables,
-            _ => CommandType::ENoCommand
+            Some(command) => ParsedCommand::EWrongUsage(command),
+            None => ParsedCommand::ENoCommand,
+        }
+    }
+}
+
+// one line per command of the given permission level
+pub fn help<C, R>(commands: &[Command<C, R>], permission: Permission) -> String {
+    commands
+        .iter()
+        .filter(|command| command.permission == permission)
+        .map(|command| format!("{} - {}\n", command.usage(), command.description))
+        .collect()
+}
+
+// (command, description) pairs for the telegram menu, which shows
+// only commands everybody can use and wants their names without a slash
+pub fn menu<C, R>(commands: &[Command<C, R>]) -> Vec<(String, String)> {
+    commands
+        .iter()
+        .filter(|command| command.permission == Permission::Everyone)
+        .map(|command| (command.name.trim_start_matches('/').to_lowercase(), String::from(command.description)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(_: &mut (), _: &[String]) -> &'static str {
+        "name"
+    }
+
+    static COMMANDS: &[Command<(), &'static str>] = &[
+        Command {
+            name: "/say",
+            aliases: &["/s"],
+            args: &[Arg::Required("word"), Arg::Optional("count"), Arg::Rest("more")],
+            permission: Permission::Everyone,
+            description: "says words",
+            handler: name,
+        },
+        Command {
+            name: "/length",
+            aliases: &[],
+            args: &[Arg::Optional("min"), Arg::Optional("max")],
+            permission: Permission::Admin,
+            description: "sets the length",
+            handler: name,
+        },
+    ];
+
+    fn parser(username: Option<&str>) -> CommandParser<(), &'static str> {
+        CommandParser::new(username.map(String::from), COMMANDS)
+    }
+
+    fn args(parsed: ParsedCommand<(), &'static str>) -> Option<(&'static str, Vec<String>)> {
+        match parsed {
+            ParsedCommand::ECommand(command, args) => Some((command.name, args)),
+            _ => None,
         }
     }
+
+    #[test]
+    fn quoted_words_are_one_token() {
+        assert_eq!(tokenize(r#"  /say "two words"  and "" more "#), vec!["/say", "two words", "and", "", "more"]);
+        assert_eq!(tokenize(r#"/say "not closed  here"#), vec!["/say", "not closed  here"]);
+        assert_eq!(tokenize("  "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn commands_of_other_bots_are_foreign() {
+        let parser = parser(Some("ZhelezyakaBot"));
+        assert!(matches!(parser.parse_command("/say@OtherBot hi"), ParsedCommand::EForeignCommand));
+        assert_eq!(args(parser.parse_command("/say@zhelezyakabot hi")), Some(("/say", vec![String::from("hi")])));
+        assert_eq!(args(parser.parse_command("/s hi")), Some(("/say", vec![String::from("hi")])));
+        assert!(matches!(parser.parse_command("just /say hi"), ParsedCommand::ENoCommand));
+        assert!(matches!(parser.parse_command("/unknown"), ParsedCommand::ENoCommand));
+    }
+
+    #[test]
+    fn without_a_username_only_unaddressed_commands_are_ours() {
+        let parser = parser(None);
+        assert!(matches!(parser.parse_command("/say@ZhelezyakaBot hi"), ParsedCommand::EForeignCommand));
+        assert_eq!(args(parser.parse_command("/say hi")), Some(("/say", vec![String::from("hi")])));
+    }
+
+    #[test]
+    fn arguments_are_counted() {
+        let parser = parser(Some("ZhelezyakaBot"));
+        assert!(matches!(parser.parse_command("/say"), ParsedCommand::EWrongUsage(command) if command.name == "/say"));
+        assert_eq!(
+            args(parser.parse_command(r#"/say a 2 b "c d" e"#)),
+            Some(("/say", vec!["a", "2", "b", "c d", "e"].into_iter().map(String::from).collect()))
+        );
+        assert_eq!(args(parser.parse_command("/length")), Some(("/length", Vec::new())));
+        assert_eq!(args(parser.parse_command("/length 1 5")).map(|(_, args)| args.len()), Some(2));
+        assert!(matches!(parser.parse_command("/length 1 5 9"), ParsedCommand::EWrongUsage(command) if command.name == "/length"));
+    }
+
+    #[test]
+    fn usage_shows_every_argument() {
+        assert_eq!(COMMANDS[0].usage(), "/say <word> [count] [more...]");
+        assert_eq!(help(COMMANDS, Permission::Admin), "/length [min] [max] - sets the length\n");
+        assert_eq!(menu(COMMANDS), vec![(String::from("say"), String::from("says words"))]);
+    }
 }
@@ -0,0 +1,798 @@
+use crate::cmd::{self, Arg, Command, Permission};
+use crate::error::Result;
+use crate::generator::{Generator, Limits, MAXIMUM_TEMPERATURE};
+use crate::sqlite::SqliteConn;
+use crate::store::*;
+use crate::telegram::TelegramActions::{self, *};
+use crate::telegram::MESSAGE_LIMIT_CHARS;
+use crate::user::UserAccount;
+use crate::{lexeme_store, SCRATCH_STORE, USER_MANAGER};
+use log::info;
+use telegram_bot::UserId;
+
+// Everything a command handler may need about the message it answers
+pub struct Context {
+    pub user_id: UserId,
+    pub sqlite: SqliteConn,
+    pub user_account: UserAccount,
+    pub table_name: TableName,
+}
+
+impl Context {
+    pub fn store(&self) -> &dyn LexemeStore {
+        lexeme_store(&self.table_name, &self.sqlite)
+    }
+
+    // the generator as this chat has it set up
+    pub fn generator(&self) -> Result<Generator<'_>> {
+        Ok(Generator::new(self.store(), &self.table_name, self.user_account.temperature)?
+            .with_limits(self.user_account.limits)
+            .with_max_copied(self.user_account.max_copied)
+            .with_source(self.user_account.source.clone()))
+    }
+}
+
+pub type BotCommand = Command<Context, Result<TelegramActions>>;
+
+// Adding a command is adding an entry here and its handler below,
+// help and the telegram menu follow by themselves
+pub static COMMANDS: &[BotCommand] = &[
+    Command {
+        name: "/q",
+        aliases: &[],
+        args: &[Arg::Rest("phrase")],
+        permission: Permission::Everyone,
+        description: "query funny story this awesome bot :)))), containing the phrase if there is one, words=MIN-MAX and chars=MIN-MAX limit its length",
+        handler: generate,
+    },
+    Command {
+        name: "/bridge",
+        aliases: &[],
+        args: &[Arg::Required("word"), Arg::Required("word"), Arg::Rest("limit")],
+        permission: Permission::Everyone,
+        description: "a funny story containing both words, words=MIN-MAX and chars=MIN-MAX limit its length",
+        handler: bridge,
+    },
+    Command {
+        name: "/on",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Everyone,
+        description: "enable answer mode for this room/chat",
+        handler: enable_for_chat,
+    },
+    Command {
+        name: "/off",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Everyone,
+        description: "disable answer mode for this room/chat",
+        handler: disable_for_chat,
+    },
+    Command {
+        name: "/learn",
+        aliases: &[],
+        args: &[Arg::Optional("on|off")],
+        permission: Permission::Everyone,
+        description: "get or set whether the bot learns from messages of this room/chat",
+        handler: learn,
+    },
+    Command {
+        name: "/readonly",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Everyone,
+        description: "answer from the current table but never learn from this room/chat",
+        handler: read_only,
+    },
+    Command {
+        name: "/probability",
+        aliases: &[],
+        args: &[Arg::Optional("0-1")],
+        permission: Permission::Everyone,
+        description: "get or set the chance to answer a message nobody addressed to the bot",
+        handler: probability,
+    },
+    Command {
+        name: "/mentiononly",
+        aliases: &[],
+        args: &[Arg::Optional("on|off")],
+        permission: Permission::Everyone,
+        description: "answer only mentions, replies to the bot and triggers",
+        handler: mention_only,
+    },
+    Command {
+        name: "/trigger",
+        aliases: &["/triggers"],
+        args: &[Arg::Optional("add|remove"), Arg::Optional("word")],
+        permission: Permission::Everyone,
+        description: "list, add or remove words which always get an answer",
+        handler: trigger,
+    },
+    Command {
+        name: "/cooldown",
+        aliases: &[],
+        args: &[Arg::Optional("seconds")],
+        permission: Permission::Everyone,
+        description: "get or set how long the bot stays silent after an answer",
+        handler: cooldown,
+    },
+    Command {
+        name: "/seeded",
+        aliases: &[],
+        args: &[Arg::Optional("on|off")],
+        permission: Permission::Everyone,
+        description: "answer around a rare word of the message instead of randomly",
+        handler: seeded_replies,
+    },
+    Command {
+        name: "/length",
+        aliases: &[],
+        args: &[Arg::Optional("words|chars"), Arg::Optional("min-max|off")],
+        permission: Permission::Everyone,
+        description: "get or set how many words and characters answers have, like /length words 5-20",
+        handler: length,
+    },
+    Command {
+        name: "/novelty",
+        aliases: &[],
+        args: &[Arg::Optional("words|off")],
+        permission: Permission::Everyone,
+        description: "get or set how many words in a row answers may copy from a single message",
+        handler: novelty,
+    },
+    Command {
+        name: "/source",
+        aliases: &[],
+        args: &[Arg::Optional("any|here|chat|document")],
+        permission: Permission::Everyone,
+        description: "get or set what answers are made of: anything, messages of this chat, all chat messages or uploaded documents",
+        handler: source,
+    },
+    Command {
+        name: "/forgetme",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Everyone,
+        description: "forget everything the bot learned from your messages",
+        handler: forget_me,
+    },
+    Command {
+        name: "/settings",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Everyone,
+        description: "show all settings of this room/chat",
+        handler: settings,
+    },
+    Command {
+        name: "/count",
+        aliases: &[],
+        args: &[Arg::Required("word")],
+        permission: Permission::Everyone,
+        description: "count word in your lexeme table",
+        handler: count,
+    },
+    Command {
+        name: "/temperature",
+        aliases: &["/temp"],
+        args: &[Arg::Optional("value")],
+        permission: Permission::Everyone,
+        description: "get or set randomness of answers, 0 is the most predictable",
+        handler: temperature,
+    },
+    Command {
+        name: "/help",
+        aliases: &["/start"],
+        args: &[],
+        permission: Permission::Everyone,
+        description: "this help",
+        handler: help,
+    },
+    Command {
+        name: "/adminhelp",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Admin,
+        description: "only if you're admin of this bot",
+        handler: admin_help,
+    },
+    Command {
+        name: "/changetable",
+        aliases: &[],
+        args: &[Arg::Required("table"), Arg::Optional("order")],
+        permission: Permission::Admin,
+        description: "change lexeme table for this room/chat, a new table gets the given order (2-5), scratch_* tables live in memory only",
+        handler: change_table,
+    },
+    Command {
+        name: "/getcurrenttable",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Admin,
+        description: "get current table for this room/chat",
+        handler: current_table,
+    },
+    Command {
+        name: "/listtable",
+        aliases: &["/listtables"],
+        args: &[],
+        permission: Permission::Admin,
+        description: "list of lexeme tables",
+        handler: list_tables,
+    },
+    Command {
+        name: "/reindex",
+        aliases: &[],
+        args: &[Arg::Optional("table")],
+        permission: Permission::Admin,
+        description: "rebuild indexes of all lexeme tables or of the given one",
+        handler: reindex,
+    },
+    Command {
+        name: "/provenance",
+        aliases: &[],
+        args: &[Arg::Required("word")],
+        permission: Permission::Admin,
+        description: "where n-grams with the word in your lexeme table were learned from",
+        handler: provenance,
+    },
+    Command {
+        name: "/forget",
+        aliases: &[],
+        args: &[Arg::Required("user id")],
+        permission: Permission::Admin,
+        description: "forget everything the bot learned from a user with the given id",
+        handler: forget_user,
+    },
+    Command {
+        name: "/batches",
+        aliases: &[],
+        args: &[],
+        permission: Permission::Admin,
+        description: "list the latest documents and message batches learned into your lexeme table",
+        handler: list_batches,
+    },
+    Command {
+        name: "/undo",
+        aliases: &[],
+        args: &[Arg::Optional("batch"), Arg::Optional("table")],
+        permission: Permission::Admin,
+        description: "take back everything the batch of your lexeme table or the given one added, the latest batch by default",
+        handler: undo,
+    },
+    Command {
+        name: "/grant",
+        aliases: &[],
+        args: &[Arg::Required("user id")],
+        permission: Permission::Admin,
+        description: "make a user with the given id an admin",
+        handler: grant_admin,
+    },
+    Command {
+        name: "/revoke",
+        aliases: &[],
+        args: &[Arg::Required("user id")],
+        permission: Permission::Admin,
+        description: "take admin rights from a user with the given id",
+        handler: revoke_admin,
+    },
+];
+
+// "5-20", "5-" or "-20", a missing bound is no limit
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let mut bounds = range.splitn(2, '-').map(|bound| match bound {
+        "" => Some(0),
+        bound => bound.parse::<usize>().ok(),
+    });
+    match (bounds.next().flatten(), bounds.next().flatten()) {
+        (Some(min), Some(max)) if max == 0 || min <= max => Some((min, max)),
+        _ => None,
+    }
+}
+
+fn describe_range(min: usize, max: usize) -> String {
+    match (min, max) {
+        (0, 0) => String::from("any"),
+        (min, 0) => format!("{}-", min),
+        (min, max) => format!("{}-{}", min, max),
+    }
+}
+
+fn describe_limits(limits: &Limits) -> String {
+    format!(
+        "words {}, chars {}",
+        describe_range(limits.min_words, limits.max_words),
+        describe_range(limits.min_chars, limits.max_chars)
+    )
+}
+
+// false if there is no such kind of limit or no answer could meet it
+fn set_range(limits: &mut Limits, kind: &str, (min, max): (usize, usize)) -> bool {
+    let mut changed = *limits;
+    match kind {
+        "words" => {
+            changed.min_words = min;
+            changed.max_words = max;
+        },
+        "chars" => {
+            changed.min_chars = min;
+            changed.max_chars = max;
+        },
+        _ => return false,
+    }
+    if !changed.is_possible() {
+        return false
+    }
+    *limits = changed;
+    true
+}
+
+// `words=MIN-MAX` and `chars=MIN-MAX` arguments override the chat limits for
+// a single request and are taken out of the rest, None if a range is wrong
+fn split_limits(mut limits: Limits, args: &[String]) -> Option<(Limits, Vec<String>)> {
+    let mut rest = Vec::new();
+    for arg in args {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next().map(str::to_lowercase), parts.next()) {
+            (Some(kind), Some(range)) if kind == "words" || kind == "chars" => {
+                if !set_range(&mut limits, &kind, parse_range(range)?) {
+                    return None
+                }
+            },
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Some((limits, rest))
+}
+
+fn wrong_limits() -> String {
+    format!("Limits look like words=MIN-MAX or chars=MIN-MAX, answers have {} chars at most", MESSAGE_LIMIT_CHARS)
+}
+
+fn generate(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let (limits, phrase) = match split_limits(ctx.user_account.limits, args) {
+        Some((limits, rest)) => (limits, rest.join(" ")),
+        None => return Ok(ReplyToMessage(wrong_limits())),
+    };
+    let answer = ctx.generator()?.with_limits(limits).select(&phrase)?;
+    Ok(ReplyToMessage(answer))
+}
+
+fn bridge(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let (limits, args) = match split_limits(ctx.user_account.limits, args) {
+        Some((limits, rest)) if rest.len() == 2 => (limits, rest),
+        Some(_) => return Ok(ReplyToMessage(String::from("Usage: /bridge <word> <word> [limit...]"))),
+        None => return Ok(ReplyToMessage(wrong_limits())),
+    };
+    let generator = ctx.generator()?.with_limits(limits);
+    match generator.bridge(&args[0], &args[1])? {
+        Some(answer) => Ok(ReplyToMessage(answer)),
+        None if limits == Limits::default() => Ok(ReplyToMessage(format!("No connection between {} and {}", args[0], args[1]))),
+        None => Ok(ReplyToMessage(format!("No connection between {} and {} within the limits", args[0], args[1]))),
+    }
+}
+
+fn set_answer_mode(ctx: &mut Context, answer_mode: bool) -> Result<TelegramActions> {
+    info!("Set answer mode {} for chat {}", answer_mode, &ctx.user_account.user_id);
+    ctx.user_account.answer_mode = answer_mode;
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(NoReply)
+}
+
+fn enable_for_chat(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    set_answer_mode(ctx, true)
+}
+
+fn disable_for_chat(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    set_answer_mode(ctx, false)
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+fn parse_on_off(arg: &str) -> Option<bool> {
+    match arg.to_lowercase().as_str() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn describe_modes(user: &UserAccount) -> String {
+    format!("Learning is {}, answering is {}", on_off(user.learn_mode), on_off(user.answer_mode))
+}
+
+fn learn(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let learn_mode = match args.first().map(|arg| parse_on_off(arg)) {
+        None => return Ok(ReplyToMessage(describe_modes(&ctx.user_account))),
+        Some(Some(learn_mode)) => learn_mode,
+        Some(None) => return Ok(ReplyToMessage(String::from("Usage: /learn [on|off]"))),
+    };
+
+    info!("Set learn mode {} for chat {}", learn_mode, &ctx.user_account.user_id);
+    ctx.user_account.learn_mode = learn_mode;
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(ReplyToMessage(describe_modes(&ctx.user_account)))
+}
+
+// the chat keeps getting answers, the table stays exactly as it is
+fn read_only(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    info!("Set read-only mode for chat {}", &ctx.user_account.user_id);
+    ctx.user_account.learn_mode = false;
+    ctx.user_account.answer_mode = true;
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(ReplyToMessage(describe_modes(&ctx.user_account)))
+}
+
+fn probability(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let value = match args.first() {
+        Some(value) => value,
+        None => return Ok(ReplyToMessage(format!("Your current reply probability is: {}", ctx.user_account.reply_probability))),
+    };
+
+    match value.parse::<f64>() {
+        Ok(p) if p >= 0.0 && p <= 1.0 => {
+            info!("Set reply probability {} for chat {}", p, &ctx.user_account.user_id);
+            ctx.user_account.reply_probability = p;
+            USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+            Ok(ReplyToMessage(format!("Reply probability is set to {}", p)))
+        },
+        _ => Ok(ReplyToMessage(String::from("Reply probability should be a number from 0 to 1"))),
+    }
+}
+
+fn mention_only(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let mention_only = match args.first().map(|arg| parse_on_off(arg)) {
+        None => return Ok(ReplyToMessage(format!("Mention only mode is {}", on_off(ctx.user_account.mention_only)))),
+        Some(Some(mention_only)) => mention_only,
+        Some(None) => return Ok(ReplyToMessage(String::from("Usage: /mentiononly [on|off]"))),
+    };
+
+    info!("Set mention only mode {} for chat {}", mention_only, &ctx.user_account.user_id);
+    ctx.user_account.mention_only = mention_only;
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(ReplyToMessage(format!("Mention only mode is {}", on_off(mention_only))))
+}
+
+fn trigger(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let (action, word) = match args {
+        [] => return Ok(ReplyToMessage(format!("Triggers: {}", ctx.user_account.triggers.join(", ")))),
+        [action, word] => (action.to_lowercase(), word.to_lowercase()),
+        _ => return Ok(ReplyToMessage(String::from("Usage: /trigger [add|remove] [word]"))),
+    };
+
+    if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+        return Ok(ReplyToMessage(String::from("A trigger is a single word of letters and digits")))
+    }
+
+    let triggers = &mut ctx.user_account.triggers;
+    match action.as_str() {
+        "add" if !triggers.contains(&word) => triggers.push(word),
+        "add" => return Ok(ReplyToMessage(format!("{} is already a trigger", word))),
+        "remove" if triggers.contains(&word) => triggers.retain(|trigger| *trigger != word),
+        "remove" => return Ok(ReplyToMessage(format!("{} is not a trigger", word))),
+        _ => return Ok(ReplyToMessage(String::from("Usage: /trigger [add|remove] [word]"))),
+    }
+
+    info!("Set triggers {:?} for chat {}", &ctx.user_account.triggers, &ctx.user_account.user_id);
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(ReplyToMessage(format!("Triggers: {}", ctx.user_account.triggers.join(", "))))
+}
+
+fn cooldown(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let value = match args.first() {
+        Some(value) => value,
+        None => return Ok(ReplyToMessage(format!("Your current cooldown is: {} seconds", ctx.user_account.cooldown))),
+    };
+
+    match value.parse::<i64>() {
+        Ok(seconds) if seconds >= 0 => {
+            info!("Set cooldown {} for chat {}", seconds, &ctx.user_account.user_id);
+            ctx.user_account.cooldown = seconds;
+            USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+            Ok(ReplyToMessage(format!("Cooldown is set to {} seconds", seconds)))
+        },
+        _ => Ok(ReplyToMessage(String::from("Cooldown should be a number of seconds"))),
+    }
+}
+
+fn seeded_replies(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let seeded_replies = match args.first().map(|arg| parse_on_off(arg)) {
+        None => return Ok(ReplyToMessage(format!("Seeded replies are {}", on_off(ctx.user_account.seeded_replies)))),
+        Some(Some(seeded_replies)) => seeded_replies,
+        Some(None) => return Ok(ReplyToMessage(String::from("Usage: /seeded [on|off]"))),
+    };
+
+    info!("Set seeded replies {} for chat {}", seeded_replies, &ctx.user_account.user_id);
+    ctx.user_account.seeded_replies = seeded_replies;
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(ReplyToMessage(format!("Seeded replies are {}", on_off(seeded_replies))))
+}
+
+fn length(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let usage = format!("Usage: /length [words|chars] [min-max|off], answers have {} chars at most", MESSAGE_LIMIT_CHARS);
+    let (kind, range) = match args {
+        [] => return Ok(ReplyToMessage(format!("Length limits: {}", describe_limits(&ctx.user_account.limits)))),
+        [kind, range] => (kind.to_lowercase(), range),
+        _ => return Ok(ReplyToMessage(usage)),
+    };
+
+    let range = if range.eq_ignore_ascii_case("off") { Some((0, 0)) } else { parse_range(range) };
+    match range {
+        Some(range) if set_range(&mut ctx.user_account.limits, &kind, range) => {
+            info!("Set length limits {:?} for chat {}", &ctx.user_account.limits, &ctx.user_account.user_id);
+            USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+            Ok(ReplyToMessage(format!("Length limits: {}", describe_limits(&ctx.user_account.limits))))
+        },
+        _ => Ok(ReplyToMessage(usage)),
+    }
+}
+
+fn describe_novelty(max_copied: usize) -> String {
+    match max_copied {
+        0 => String::from("Answers may copy whole messages"),
+        words => format!("Answers copy at most {} words in a row from a message", words),
+    }
+}
+
+fn novelty(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let value = match args.first() {
+        Some(value) => value,
+        None => return Ok(ReplyToMessage(describe_novelty(ctx.user_account.max_copied))),
+    };
+
+    let max_copied = if value.eq_ignore_ascii_case("off") { Some(0) } else { value.parse::<usize>().ok() };
+    match max_copied {
+        Some(words) => {
+            info!("Set max copied words {} for chat {}", words, &ctx.user_account.user_id);
+            ctx.user_account.max_copied = words;
+            USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+            Ok(ReplyToMessage(describe_novelty(words)))
+        },
+        None => Ok(ReplyToMessage(String::from("Usage: /novelty [words|off]"))),
+    }
+}
+
+fn source(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let source = match args.first().map(|arg| arg.to_lowercase()) {
+        None => return Ok(ReplyToMessage(format!("Sources: {}", ctx.user_account.source))),
+        Some(arg) => match arg.as_str() {
+            "any" => SourceFilter::any(),
+            "here" => SourceFilter { chat: Some(ctx.user_account.user_id.clone()), kind: None },
+            kind => match SourceKind::parse(kind) {
+                Some(kind) => SourceFilter { chat: None, kind: Some(kind) },
+                None => return Ok(ReplyToMessage(String::from("Usage: /source [any|here|chat|document]"))),
+            },
+        },
+    };
+
+    info!("Set sources {} for chat {}", source, &ctx.user_account.user_id);
+    ctx.user_account.source = source;
+    USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+    Ok(ReplyToMessage(format!("Sources: {}", ctx.user_account.source)))
+}
+
+// on disk and in memory, the report sums both
+fn forget(ctx: &Context, user: &str) -> Result<String> {
+    let mut forgotten = ctx.sqlite.forget(user)?;
+    forgotten.add(SCRATCH_STORE.forget(user)?);
+
+    info!("Forgot user {}: {:?}", user, forgotten);
+    Ok(format!("Forgot {}", describe_forgotten(&forgotten)))
+}
+
+fn describe_forgotten(forgotten: &Forgotten) -> String {
+    format!(
+        "{} n-grams learned {} times, {} of them nobody else taught and they are gone",
+        forgotten.ngrams, forgotten.count, forgotten.removed
+    )
+}
+
+fn forget_me(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    Ok(ReplyToMessage(forget(ctx, &ctx.user_id.to_string())?))
+}
+
+fn settings(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    let user = &ctx.user_account;
+    Ok(ReplyToMessage(format!(
+        "{}\nTable: {}\nTemperature: {}\nReply probability: {}\nMention only: {}\nTriggers: {}\nCooldown: {} seconds\nSeeded replies: {}\nLength: {}\nNovelty: {}\nSources: {}",
+        describe_modes(user),
+        user.lexeme_table,
+        user.temperature,
+        user.reply_probability,
+        on_off(user.mention_only),
+        user.triggers.join(", "),
+        user.cooldown,
+        on_off(user.seeded_replies),
+        describe_limits(&user.limits),
+        describe_novelty(user.max_copied),
+        user.source
+    )))
+}
+
+fn count(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    Ok(ReplyToMessage(format!("Count {}", ctx.store().count(&ctx.table_name, &args[0], &ctx.user_account.source)?)))
+}
+
+fn temperature(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let value = match args.first() {
+        Some(value) => value,
+        None => return Ok(ReplyToMessage(format!("Your current temperature is: {}", ctx.user_account.temperature))),
+    };
+
+    match value.parse::<f64>() {
+        Ok(t) if t >= 0.0 && t <= MAXIMUM_TEMPERATURE => {
+            info!("Set temperature {} for chat {}", t, &ctx.user_account.user_id);
+            ctx.user_account.temperature = t;
+            USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+            Ok(ReplyToMessage(format!("Temperature is set to {}", t)))
+        },
+        _ => Ok(ReplyToMessage(format!("Temperature should be a number from 0 to {}", MAXIMUM_TEMPERATURE))),
+    }
+}
+
+fn help(_ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    Ok(ReplyToMessage(format!("JelezyakaBot 2.0:\n{}", cmd::help(COMMANDS, Permission::Everyone))))
+}
+
+fn admin_help(_ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    Ok(ReplyToMessage(format!("EBALO AUF NUL!\n{}", cmd::help(COMMANDS, Permission::Admin))))
+}
+
+const BAD_TABLE_NAME: &str = "Bad table name, use latin letters, digits and '_' only, but no '__'";
+
+fn change_table(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let order = match args.get(1) {
+        Some(order) => order.parse::<usize>().ok().filter(|o| *o >= MINIMUM_ORDER && *o <= MAXIMUM_ORDER),
+        None => Some(DEFAULT_ORDER),
+    };
+
+    match (TableName::new(&args[0]), order) {
+        (Some(table), Some(order)) => {
+            let order = lexeme_store(&table, &ctx.sqlite).create_lexeme_table(&table, order)?;
+            ctx.user_account.lexeme_table = String::from(table.as_str());
+            USER_MANAGER.update_user(&ctx.sqlite, &ctx.user_account)?;
+            Ok(ReplyToMessage(format!("Created table {} of order {}", &table, order)))
+        },
+        (None, _) => Ok(ReplyToMessage(String::from(BAD_TABLE_NAME))),
+        (_, None) => Ok(ReplyToMessage(format!("Order should be a number from {} to {}", MINIMUM_ORDER, MAXIMUM_ORDER))),
+    }
+}
+
+fn current_table(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    Ok(ReplyToMessage(format!("Your current lexeme table is: {}", &ctx.user_account.lexeme_table)))
+}
+
+fn list_tables(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    let mut tables = ctx.sqlite.fetch_lexems_tables_list()?;
+    tables.extend(SCRATCH_STORE.fetch_lexems_tables_list()?);
+    Ok(ReplyToMessage(format!("List of lexeme tables - {}", tables.join(","))))
+}
+
+fn reindex(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let tables = ctx.sqlite.fetch_lexems_tables_list()?
+        .iter()
+        .filter_map(|t| TableName::new(t))
+        .filter(|t| args.first().map_or(true, |table| TableName::new(table).as_ref() == Some(t)))
+        .collect::<Vec<TableName>>();
+
+    for table in &tables {
+        lexeme_store(table, &ctx.sqlite).reindex(table)?;
+    }
+    Ok(ReplyToMessage(format!("Reindexed {} tables", tables.len())))
+}
+
+// n-grams learned before sources were tracked have no dates
+fn describe_provenance(provenance: &Provenance, now: i64) -> String {
+    let days = |time: i64| (now - time) / (24 * 60 * 60);
+    match provenance.source.kind {
+        SourceKind::Unknown => format!("unknown: {}", provenance.count),
+        kind => format!(
+            "{} {}: {}, first seen {} days ago, last seen {} days ago",
+            kind,
+            provenance.source.chat,
+            provenance.count,
+            days(provenance.first_seen),
+            days(provenance.last_seen)
+        ),
+    }
+}
+
+fn provenance(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let sources = ctx.store().sources(&ctx.table_name, &args[0])?;
+    if sources.is_empty() {
+        return Ok(ReplyToMessage(format!("{} is not in {}", args[0], ctx.table_name)))
+    }
+
+    let now = unix_time();
+    let lines = sources.iter().map(|p| describe_provenance(p, now)).collect::<Vec<String>>();
+    Ok(ReplyToMessage(format!("Sources of {}:\n{}", args[0], lines.join("\n"))))
+}
+
+fn forget_user(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    if args[0].parse::<i64>().is_err() {
+        return Ok(ReplyToMessage(String::from("User id should be a number")))
+    }
+
+    info!("User {} makes the bot forget {}", ctx.user_id, args[0]);
+    Ok(ReplyToMessage(forget(ctx, &args[0])?))
+}
+
+// how many batches /batches shows and /undo looks through
+const LISTED_BATCHES: usize = 10;
+
+fn describe_batch(batch: &Batch, now: i64) -> String {
+    let minutes = (now - batch.created_at) / 60;
+    let age = match minutes {
+        m if m < 60 => format!("{} minutes ago", m),
+        m if m < 24 * 60 => format!("{} hours ago", m / 60),
+        m => format!("{} days ago", m / (24 * 60)),
+    };
+    let undone = if batch.undone { ", undone" } else { "" };
+    format!("{}: {} {}, {}{}", batch.id, batch.source.kind, batch.source.chat, age, undone)
+}
+
+fn list_batches(ctx: &mut Context, _args: &[String]) -> Result<TelegramActions> {
+    let batches = ctx.store().batches(&ctx.table_name, LISTED_BATCHES)?;
+    if batches.is_empty() {
+        return Ok(ReplyToMessage(format!("Nothing was learned into {} yet", ctx.table_name)))
+    }
+
+    let now = unix_time();
+    let lines = batches.iter().map(|batch| describe_batch(batch, now)).collect::<Vec<String>>();
+    Ok(ReplyToMessage(format!("Batches of {}:\n{}", ctx.table_name, lines.join("\n"))))
+}
+
+// Batches of another table are only undone when the table is named, ids of
+// scratch tables and tables on disk overlap, so the table picks the store too
+fn undo(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    let table = match args.get(1) {
+        Some(table) => match TableName::new(table) {
+            Some(table) => table,
+            None => return Ok(ReplyToMessage(String::from(BAD_TABLE_NAME))),
+        },
+        None => ctx.table_name.clone(),
+    };
+    let store = lexeme_store(&table, &ctx.sqlite);
+
+    let id = match args.first() {
+        Some(id) => match id.parse::<i64>() {
+            Ok(id) => id,
+            Err(_) => return Ok(ReplyToMessage(String::from("Batch should be a number, see /batches"))),
+        },
+        None => match store.batches(&table, LISTED_BATCHES)?.into_iter().find(|batch| !batch.undone) {
+            Some(batch) => batch.id,
+            None => return Ok(ReplyToMessage(format!("Nothing to undo in {}", table))),
+        },
+    };
+
+    info!("User {} undoes batch {} of {}", ctx.user_id, id, table);
+    match store.undo(&table, id)? {
+        Some(forgotten) => Ok(ReplyToMessage(format!("Undid batch {} of {}: {}", id, table, describe_forgotten(&forgotten)))),
+        None => Ok(ReplyToMessage(format!("There is no batch {} in {} or it's undone already, see /batches", id, table))),
+    }
+}
+
+fn set_admin(ctx: &mut Context, user: &str, is_admin: bool) -> Result<TelegramActions> {
+    if user.parse::<i64>().is_err() {
+        return Ok(ReplyToMessage(String::from("User id should be a number")))
+    }
+
+    info!("User {} sets admin rights of {} to {}", ctx.user_id, user, is_admin);
+    USER_MANAGER.set_admin(&ctx.sqlite, user, is_admin)?;
+    if is_admin {
+        Ok(ReplyToMessage(format!("User {} is now an admin", user)))
+    } else {
+        Ok(ReplyToMessage(format!("User {} is no longer an admin", user)))
+    }
+}
+
+fn grant_admin(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    set_admin(ctx, &args[0], true)
+}
+
+fn revoke_admin(ctx: &mut Context, args: &[String]) -> Result<TelegramActions> {
+    set_admin(ctx, &args[0], false)
+}
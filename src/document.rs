@@ -0,0 +1,416 @@
+use crate::error::{Error, Result};
+use flate2::read::MultiGzDecoder;
+use log::{debug, info};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+// Turns an uploaded file into texts to learn. Compressed files are decoded as
+// they are read, and it's the decoded size which is limited, not the upload.
+
+// all files of an archive together can't decode into more than this, the
+// decoded text and its split messages are all held in memory while learned
+pub const DECODED_SIZE_LIMIT_BYTES: u64 = 32_000_000; // 32 MB
+
+// in a caption of a csv file, the name or the number of the column to learn
+pub const COLUMN_PREFIX: &str = "column=";
+
+// in a caption, how texts are split into messages
+pub const SPLIT_PREFIX: &str = "split=";
+
+// csv files without a chosen column are learned from this one, if they have it
+const DEFAULT_COLUMN: &str = "text";
+
+const SENTENCE_TERMINATORS: &[char] = &['.', '!', '?', '…'];
+// may follow the terminator and still belong to the sentence
+const SENTENCE_CLOSINGS: &[char] = &['"', '\'', ')', ']', '»', '”', '’'];
+
+const TEXT_EXTENSIONS: &[&str] = &["txt", "md", "csv", "json"];
+const ARCHIVE_EXTENSIONS: &[&str] = &["gz", "zip"];
+
+pub enum Contents {
+    // plain text or markdown without markup, split into messages as the caption says
+    Text(String),
+    // a message per cell of the csv column
+    Cells(Vec<String>),
+    // a chat export of telegram desktop
+    Export(String),
+}
+
+// Texts are learned by lines, which suits poems and chat logs, or by sentences,
+// which suits books with lines wrapped anywhere. Cells of csv files and messages
+// of chat exports are messages already and aren't split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Splitting {
+    Lines,
+    Sentences,
+}
+
+impl Splitting {
+    pub fn parse(splitting: &str) -> Option<Splitting> {
+        match splitting.to_lowercase().as_str() {
+            "lines" => Some(Splitting::Lines),
+            "sentences" => Some(Splitting::Sentences),
+            _ => None,
+        }
+    }
+
+    // lines are borrowed, sentences may be joined from several lines
+    pub fn split(self, text: &str) -> Vec<Cow<'_, str>> {
+        match self {
+            Splitting::Lines => text.lines().map(Cow::from).collect(),
+            Splitting::Sentences => sentences(text).into_iter().map(Cow::from).collect(),
+        }
+    }
+}
+
+impl Default for Splitting {
+    fn default() -> Splitting {
+        Splitting::Lines
+    }
+}
+
+// lines of a paragraph joined together, paragraphs are separated by empty lines
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(paragraph.join(" "));
+                paragraph.clear();
+            }
+        } else {
+            paragraph.push(line);
+        }
+    }
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph.join(" "));
+    }
+    paragraphs
+}
+
+// "J. R. R. Tolkien" goes on after every dot
+fn is_initial(sentence: &[char]) -> bool {
+    let word = sentence.iter().rev().take_while(|c| !c.is_whitespace()).collect::<Vec<&char>>();
+    word.len() == 1 && word[0].is_uppercase()
+}
+
+// A sentence ends with terminators and maybe closing quotes, followed by
+// a space and not a lowercase letter, so "e.g. this" stays in one piece.
+// A sentence never crosses a paragraph.
+fn sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    for paragraph in paragraphs(text) {
+        let chars: Vec<char> = paragraph.chars().collect();
+        let mut start = 0;
+        let mut i = 0;
+        while i < chars.len() {
+            if !SENTENCE_TERMINATORS.contains(&chars[i]) {
+                i += 1;
+                continue
+            }
+
+            let mut end = i + 1;
+            while end < chars.len() && (SENTENCE_TERMINATORS.contains(&chars[end]) || SENTENCE_CLOSINGS.contains(&chars[end])) {
+                end += 1;
+            }
+            let at_space = end == chars.len() || chars[end].is_whitespace();
+            let next_lowercase = chars[end..].iter().find(|c| !c.is_whitespace()).map_or(false, |c| c.is_lowercase());
+            let initial = chars[i] == '.' && end == i + 1 && is_initial(&chars[start..i]);
+            if at_space && !next_lowercase && !initial {
+                sentences.push(chars[start..end].iter().collect::<String>().trim().to_string());
+                start = end;
+            }
+            i = end;
+        }
+        let rest = chars[start..].iter().collect::<String>();
+        if !rest.trim().is_empty() {
+            sentences.push(rest.trim().to_string());
+        }
+    }
+    sentences
+}
+
+fn extension(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+}
+
+// archives are only looked into when they are read, so any archive will do
+pub fn is_supported(file_name: &str) -> bool {
+    extension(file_name).map_or(false, |ext| {
+        TEXT_EXTENSIONS.contains(&ext.as_str()) || ARCHIVE_EXTENSIONS.contains(&ext.as_str())
+    })
+}
+
+// reads everything from the reader unless it's more than is left of the limit
+fn read_limited<R: Read>(reader: R, left: &mut u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(*left + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > *left {
+        return Err(Error::DecodedSizeIsTooBig)
+    }
+    *left -= data.len() as u64;
+    Ok(data)
+}
+
+// the column is a header or a number starting from 1
+fn csv_column(data: &[u8], column: Option<&str>) -> Result<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+    let headers = reader.headers()?.clone();
+    let index = match column {
+        Some(column) => column
+            .parse::<usize>()
+            .ok()
+            .filter(|number| *number >= 1 && *number <= headers.len())
+            .map(|number| number - 1)
+            .or_else(|| headers.iter().position(|header| header.trim().eq_ignore_ascii_case(column))),
+        None => Some(headers.iter().position(|header| header.trim().eq_ignore_ascii_case(DEFAULT_COLUMN)).unwrap_or(0)),
+    };
+    let index = index.ok_or_else(|| Error::ColumnNotFound(String::from(column.unwrap_or_default())))?;
+
+    let mut cells = Vec::new();
+    for record in reader.records() {
+        if let Some(cell) = record?.get(index) {
+            cells.push(String::from(cell));
+        }
+    }
+    Ok(cells)
+}
+
+// drops markers of emphasis and code unless they are inside a word, like in snake_case
+fn strip_emphasis(line: &str) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut stripped = String::with_capacity(line.len());
+    for (i, c) in chars.iter().enumerate() {
+        let inside_word = i > 0
+            && i + 1 < chars.len()
+            && chars[i - 1].is_alphanumeric()
+            && chars[i + 1].is_alphanumeric();
+        match c {
+            '*' | '`' | '~' => {},
+            '_' if !inside_word => {},
+            '|' => stripped.push(' '),
+            c => stripped.push(*c),
+        }
+    }
+    stripped
+}
+
+// [text](url) and ![text](url) leave only the text
+fn strip_links(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(open) = rest.find('[') {
+        let close = match rest[open..].find("](") {
+            Some(close) => open + close,
+            None => break,
+        };
+        let end = match rest[close..].find(')') {
+            Some(end) => close + end,
+            None => break,
+        };
+        stripped.push_str(rest[..open].trim_end_matches('!'));
+        stripped.push_str(&rest[open + 1..close]);
+        rest = &rest[end + 1..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+// Keeps the words of a markdown text: code blocks, headers, quotes, lists,
+// links and emphasis are gone, every line stays a line
+fn strip_markdown(text: &str) -> String {
+    let mut code = false;
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            code = !code;
+            continue
+        }
+        let rule = trimmed.len() >= 3 && trimmed.chars().all(|c| c == '-' || c == '*' || c == '_' || c == '=');
+        if code || rule {
+            continue
+        }
+
+        let mut line = trimmed.trim_start_matches(&['#', '>', ' '][..]);
+        for marker in &["- ", "* ", "+ "] {
+            if line.starts_with(marker) {
+                line = &line[marker.len()..];
+            }
+        }
+        let digits = line.chars().take_while(char::is_ascii_digit).count();
+        if digits > 0 && line[digits..].starts_with(". ") {
+            line = &line[digits + 2..];
+        }
+        lines.push(strip_emphasis(&strip_links(line)));
+    }
+    lines.join("\n")
+}
+
+fn read_text(file_name: &str, data: Vec<u8>, column: Option<&str>) -> Result<Contents> {
+    let text = || String::from_utf8_lossy(&data).into_owned();
+    match extension(file_name).as_deref() {
+        Some("md") => Ok(Contents::Text(strip_markdown(&text()))),
+        Some("csv") => Ok(Contents::Cells(csv_column(&data, column)?)),
+        Some("json") => Ok(Contents::Export(text())),
+        Some("txt") => Ok(Contents::Text(text())),
+        _ => Err(Error::FileExtensionMissingOrWrong),
+    }
+}
+
+// every text file of the archive, whatever else is there is skipped
+fn read_zip(data: &[u8], column: Option<&str>, left: &mut u64) -> Result<Vec<Contents>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let mut contents = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        let name = String::from(file.name());
+        if file.is_dir() || !extension(&name).map_or(false, |ext| TEXT_EXTENSIONS.contains(&ext.as_str())) {
+            debug!("Skipping {} in the archive", name);
+            continue
+        }
+        let data = read_limited(file, left)?;
+        contents.push(read_text(&name, data, column)?);
+    }
+    Ok(contents)
+}
+
+// `column` is only used for csv files
+pub fn read(file_name: &str, data: &[u8], column: Option<&str>) -> Result<Vec<Contents>> {
+    let mut left = DECODED_SIZE_LIMIT_BYTES;
+    let contents = match extension(file_name).as_deref() {
+        // what's inside is named like the file without .gz, files concatenated
+        // by `cat a.gz b.gz` are several gzip members and all of them are read
+        Some("gz") => {
+            let inner = &file_name[..file_name.len() - ".gz".len()];
+            let data = read_limited(MultiGzDecoder::new(data), &mut left)?;
+            vec![read_text(inner, data, column)?]
+        },
+        Some("zip") => read_zip(data, column, &mut left)?,
+        _ => vec![read_text(file_name, read_limited(data, &mut left)?, column)?],
+    };
+    info!("Read {} of {} bytes into {} bytes", file_name, data.len(), DECODED_SIZE_LIMIT_BYTES - left);
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gz(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, text) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(text.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    // every text read, cells of a csv file one per line
+    fn texts(contents: &[Contents]) -> Vec<String> {
+        contents
+            .iter()
+            .map(|contents| match contents {
+                Contents::Text(text) | Contents::Export(text) => text.clone(),
+                Contents::Cells(cells) => cells.join("\n"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn markdown_leaves_only_words() {
+        let markdown = "# Title\n\n> a *quote* with `code`\n- [a link](http://x.org) and ![](img.png)\n\
+            12. snake_case stays, __bold__ goes\n---\n```\nlet x = 1;\n```\n| a | b |";
+        assert_eq!(
+            strip_markdown(markdown),
+            "Title\n\na quote with code\na link and \nsnake_case stays, bold goes\n  a   b  "
+        );
+    }
+
+    #[test]
+    fn csv_columns_are_found_by_header_or_number() {
+        let csv = b"id,Text,author\n1,hello there,ann\n2,\"a, b\",bob\n3\n";
+        assert_eq!(csv_column(csv, None).unwrap(), vec!["hello there", "a, b"]);
+        assert_eq!(csv_column(csv, Some("AUTHOR")).unwrap(), vec!["ann", "bob"]);
+        assert_eq!(csv_column(csv, Some("1")).unwrap(), vec!["1", "2", "3"]);
+        assert!(matches!(csv_column(csv, Some("4")), Err(Error::ColumnNotFound(column)) if column == "4"));
+        assert!(matches!(csv_column(csv, Some("date")), Err(Error::ColumnNotFound(_))));
+        assert_eq!(csv_column(b"a,b\nx,y\n", None).unwrap(), vec!["x"]);
+    }
+
+    #[test]
+    fn concatenated_gzip_is_read_whole() {
+        let data = [gz("one\n"), gz("two\n")].concat();
+        assert_eq!(texts(&read("texts.txt.gz", &data, None).unwrap()), vec!["one\ntwo\n"]);
+    }
+
+    #[test]
+    fn archives_keep_only_texts() {
+        let data = zip(&[("a.txt", "plain"), ("picture.png", "not text"), ("b.csv", "text\ncell")]);
+        assert_eq!(texts(&read("texts.zip", &data, None).unwrap()), vec!["plain", "cell"]);
+    }
+
+    #[test]
+    fn decoded_size_is_limited() {
+        let mut left = 10;
+        assert_eq!(read_limited(MultiGzDecoder::new(&gz("0123456789")[..]), &mut left).unwrap().len(), 10);
+        assert_eq!(left, 0);
+
+        let mut left = 9;
+        assert!(matches!(read_limited(MultiGzDecoder::new(&gz("0123456789")[..]), &mut left), Err(Error::DecodedSizeIsTooBig)));
+
+        // the limit is for all files of an archive together
+        let data = zip(&[("a.txt", "01234"), ("b.txt", "56789")]);
+        assert_eq!(read_zip(&data, None, &mut 10).unwrap().len(), 2);
+        assert!(matches!(read_zip(&data, None, &mut 9), Err(Error::DecodedSizeIsTooBig)));
+    }
+
+    #[test]
+    fn paragraphs_are_separated_by_empty_lines() {
+        assert_eq!(
+            paragraphs("first line\n  wrapped here.\n\nno end\n \n\n  last  "),
+            vec!["first line wrapped here.", "no end", "last"]
+        );
+        assert!(paragraphs("\n\n").is_empty());
+    }
+
+    #[test]
+    fn sentences_end_after_terminators_and_closings() {
+        assert_eq!(
+            sentences("He said \"Stop!\" Then he left. Really?! Yes…\n(Or so it was.) The end"),
+            vec!["He said \"Stop!\"", "Then he left.", "Really?!", "Yes…", "(Or so it was.)", "The end"]
+        );
+    }
+
+    #[test]
+    fn abbreviations_and_initials_go_on() {
+        assert_eq!(
+            sentences("Tools, e.g. this one, and pi is 3.14 or so. J. R. R. Tolkien wrote it."),
+            vec!["Tools, e.g. this one, and pi is 3.14 or so.", "J. R. R. Tolkien wrote it."]
+        );
+    }
+
+    #[test]
+    fn sentences_never_cross_paragraphs() {
+        assert_eq!(
+            sentences("a sentence\nwrapped without an end\n\nNext one. And\nanother."),
+            vec!["a sentence wrapped without an end", "Next one.", "And another."]
+        );
+        assert_eq!(Splitting::Lines.split("one. Two\nthree"), vec!["one. Two", "three"]);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::document::DECODED_SIZE_LIMIT_BYTES;
+use crate::migrations::MigrationError;
+use r2d2_sqlite::rusqlite;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    Migration(MigrationError),
+    Telegram(telegram_bot::Error),
+    // what went wrong with the request, never its url, which has the token in it
+    Http(String),
+    TableNotFound(String),
+    FileSizeIsTooBig,
+    FileExtensionMissingOrWrong,
+    FilePathMissing,
+    Json(serde_json::Error),
+    NotAnExport,
+    Io(std::io::Error),
+    Zip(zip::result::ZipError),
+    Csv(csv::Error),
+    DecodedSizeIsTooBig,
+    ColumnNotFound(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // what the chat sees instead of a crash, details stay in the log
+    pub fn reply(&self) -> String {
+        match self {
+            Error::TableNotFound(table) => format!("Table {} doesn't exist, pick another one with /changetable", table),
+            Error::FileSizeIsTooBig => String::from("File is too big"),
+            Error::FileExtensionMissingOrWrong => String::from(
+                "Only txt, md, csv files and json chat exports are supported, also inside gz or zip"
+            ),
+            Error::FilePathMissing => String::from("Telegram didn't give a link to this file, try again later"),
+            Error::Json(_) | Error::NotAnExport => String::from("This is not a chat export of Telegram Desktop"),
+            Error::Io(_) | Error::Zip(_) => String::from("The archive is broken"),
+            Error::Csv(e) => format!("The csv file is broken: {}", e),
+            Error::DecodedSizeIsTooBig => format!("File is too big when unpacked, {} MB at most", DECODED_SIZE_LIMIT_BYTES / 1_000_000),
+            Error::ColumnNotFound(column) => format!("There is no column {} in the csv file", column),
+            Error::Sqlite(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::DatabaseBusy
+                || e.code == rusqlite::ErrorCode::DatabaseLocked => String::from("Database is busy, try again later"),
+            _ => String::from("Something went wrong, try again later"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            Error::Pool(e) => write!(f, "connection pool error: {}", e),
+            Error::Migration(e) => write!(f, "{}", e),
+            Error::Telegram(e) => write!(f, "telegram error: {}", e),
+            Error::Http(e) => write!(f, "http error: {}", e),
+            Error::TableNotFound(table) => write!(f, "table {} doesn't exist", table),
+            Error::FileSizeIsTooBig => write!(f, "file size is too big"),
+            Error::FileExtensionMissingOrWrong => write!(f, "file extension is missing or wrong"),
+            Error::FilePathMissing => write!(f, "file path is missing"),
+            Error::Json(e) => write!(f, "json error: {}", e),
+            Error::NotAnExport => write!(f, "no messages in the chat export"),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Zip(e) => write!(f, "zip error: {}", e),
+            Error::Csv(e) => write!(f, "csv error: {}", e),
+            Error::DecodedSizeIsTooBig => write!(f, "decoded size is too big"),
+            Error::ColumnNotFound(column) => write!(f, "column {} not found", column),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Error {
+        Error::Pool(e)
+    }
+}
+
+impl From<MigrationError> for Error {
+    fn from(e: MigrationError) -> Error {
+        Error::Migration(e)
+    }
+}
+
+impl From<telegram_bot::Error> for Error {
+    fn from(e: telegram_bot::Error) -> Error {
+        Error::Telegram(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Error {
+        Error::Zip(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error::Csv(e)
+    }
+}
+
+// reqwest shows the url in its errors, and urls of the bot api have the token
+// in them, so only the status or the cause is kept
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        let what = match (e.status(), std::error::Error::source(&e)) {
+            (Some(status), _) => format!("status {}", status),
+            (None, Some(source)) => source.to_string(),
+            (None, None) => String::from("request failed"),
+        };
+        Error::Http(what)
+    }
+}
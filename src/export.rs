@@ -0,0 +1,147 @@
+use crate::error::{Error, Result};
+use log::{debug, trace};
+use serde_json::Value;
+
+// Reads `result.json` of Telegram Desktop's chat export, which is either a
+// single chat with its `messages` or a whole account with chats in `chats.list`.
+// Only plain messages of people are taken, service messages, bot messages
+// and commands are skipped.
+
+// in a caption of an export, learns only messages of the author named after it
+pub const AUTHOR_PREFIX: &str = "from=";
+
+pub struct ExportedMessage {
+    // telegram id of the author, None for channels and anything else which isn't a user
+    pub user_id: Option<String>,
+    pub text: String,
+}
+
+// `text` is either a string or an array of strings and entities like
+// {"type": "bold", "text": "..."}, for us all of them are just text
+fn flatten_text(text: &Value) -> String {
+    match text {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| match part {
+                Value::String(text) => text.as_str(),
+                Value::Object(entity) => entity.get("text").and_then(Value::as_str).unwrap_or_default(),
+                _ => "",
+            })
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+// older exports have a number, newer ones "user123" or "channel123"
+fn from_id(message: &Value) -> Option<String> {
+    match message.get("from_id")? {
+        Value::Number(id) => Some(id.to_string()),
+        Value::String(id) => Some(id.clone()),
+        _ => None,
+    }
+}
+
+// The export doesn't say who is a bot, but messages sent through inline bots
+// are marked and bots are usually named like their usernames, one word ending
+// with "Bot" or "_bot", unlike people named Talbot or Abbot
+fn is_from_bot(message: &Value, author: &str) -> bool {
+    let username_like = !author.contains(char::is_whitespace) && (author.ends_with("Bot") || author.ends_with("_bot"));
+    message.get("via_bot").is_some() || username_like
+}
+
+// authors are matched by name or by id, either as the export has it, like
+// "user42", or as telegram knows the user, like 42
+fn exported_message(message: &Value, authors: &[String]) -> Option<ExportedMessage> {
+    if message.get("type").and_then(Value::as_str) != Some("message") {
+        return None;
+    }
+
+    let author = message.get("from").and_then(Value::as_str).unwrap_or_default();
+    let from_id = from_id(message).unwrap_or_default();
+    let id = from_id.strip_prefix("user").unwrap_or(&from_id);
+    if is_from_bot(message, author) {
+        trace!("Skipping a message of bot {:?}", author);
+        return None;
+    }
+    if !authors.is_empty() && !authors.iter().any(|a| a.eq_ignore_ascii_case(author) || *a == from_id || a == id) {
+        return None;
+    }
+
+    let text = flatten_text(message.get("text")?);
+    if text.trim().is_empty() || text.trim_start().starts_with('/') {
+        return None;
+    }
+
+    let user_id = Some(id)
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+        .map(String::from);
+    Some(ExportedMessage { user_id, text })
+}
+
+// messages of the given authors only, unless there are none
+pub fn messages(json: &str, authors: &[String]) -> Result<Vec<ExportedMessage>> {
+    let export: Value = serde_json::from_str(json)?;
+    let chats: Vec<&Value> = match export.get("chats").and_then(|chats| chats.get("list")).and_then(Value::as_array) {
+        Some(list) => list.iter().collect(),
+        None => vec![&export],
+    };
+
+    let mut found = false;
+    let mut messages = Vec::new();
+    for chat in chats {
+        if let Some(list) = chat.get("messages").and_then(Value::as_array) {
+            found = true;
+            messages.extend(list.iter().filter_map(|message| exported_message(message, authors)));
+        }
+    }
+
+    if !found {
+        return Err(Error::NotAnExport);
+    }
+    debug!("Exported {} messages by {:?}", messages.len(), authors);
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"{"name": "chat", "messages": [
+        {"id": 1, "type": "service", "actor": "Ann", "action": "invite_members"},
+        {"id": 2, "type": "message", "from": "Ann", "from_id": "user42", "text": ["hello ", {"type": "bold", "text": "big"}, " world"]},
+        {"id": 3, "type": "message", "from": "WeatherBot", "from_id": "user7", "text": "sunny"},
+        {"id": 4, "type": "message", "from": "Ann", "from_id": "user42", "via_bot": "@gif", "text": "a gif"},
+        {"id": 5, "type": "message", "from": "Talbot", "from_id": 43, "text": "/start"},
+        {"id": 6, "type": "message", "from": "Talbot", "from_id": 43, "text": "talbot says hi"},
+        {"id": 7, "type": "message", "from": "News", "from_id": "channel9", "text": "news here"}]}"#;
+
+    fn texts(messages: &[ExportedMessage]) -> Vec<(Option<&str>, &str)> {
+        messages.iter().map(|m| (m.user_id.as_deref(), m.text.as_str())).collect()
+    }
+
+    #[test]
+    fn people_messages_are_taken() {
+        let messages = messages(EXPORT, &[]).unwrap();
+        assert_eq!(
+            texts(&messages),
+            vec![(Some("42"), "hello big world"), (Some("43"), "talbot says hi"), (None, "news here")]
+        );
+    }
+
+    #[test]
+    fn authors_are_filtered_by_name_or_id() {
+        assert_eq!(texts(&messages(EXPORT, &[String::from("ann")]).unwrap()), vec![(Some("42"), "hello big world")]);
+        assert_eq!(texts(&messages(EXPORT, &[String::from("43")]).unwrap()), vec![(Some("43"), "talbot says hi")]);
+        assert_eq!(texts(&messages(EXPORT, &[String::from("42")]).unwrap()), vec![(Some("42"), "hello big world")]);
+        assert_eq!(texts(&messages(EXPORT, &[String::from("user42")]).unwrap()), vec![(Some("42"), "hello big world")]);
+    }
+
+    #[test]
+    fn whole_account_exports_are_read() {
+        let account = format!(r#"{{"chats": {{"list": [{}, {}]}}}}"#, EXPORT, EXPORT);
+        assert_eq!(messages(&account, &[]).unwrap().len(), 6);
+        assert!(matches!(messages("{}", &[]), Err(Error::NotAnExport)));
+        assert!(matches!(messages("nope", &[]), Err(Error::Json(_))));
+    }
+}
@@ -0,0 +1,638 @@
+use crate::error::Result;
+use crate::store::*;
+use crate::telegram::MESSAGE_LIMIT_CHARS;
+use log::{debug, trace};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+pub const DEFAULT_TEMPERATURE: f64 = 1.0;
+// anything hotter than this is just a uniform pick among continuations
+pub const MAXIMUM_TEMPERATURE: f64 = 10.0;
+// words in a row an answer may take from a single message
+pub const DEFAULT_MAXIMUM_COPIED: usize = 8;
+
+const NOT_FOUND: &str = "Not found";
+
+// maximum length of recursion limit in select_left and select_right functions
+const MAXIMUM_RECURSION_DEPTH: i32 = 500;
+
+// texts not fitting the limits or copying a message are generated again
+// this many times at most
+const MAXIMUM_ATTEMPTS: usize = 10;
+
+// how many n-grams starting a phrase are tried before giving up on it
+const MAXIMUM_PHRASE_ATTEMPTS: usize = 20;
+
+// a bridge between two words is searched among chains growing by at most
+// this many words, looking at no more than that many contexts
+const MAXIMUM_BRIDGE_LENGTH: usize = 12;
+const MAXIMUM_BRIDGE_CONTEXTS: usize = 2000;
+
+// shorter words and the stopwords below say nothing about a message, so
+// they never seed a reply
+const MINIMUM_SEED_LENGTH: usize = 3;
+// only so many distinct words of a message are looked up
+const MAXIMUM_SEED_CANDIDATES: usize = 20;
+const STOPWORDS: &[&str] = &[
+    "the", "and", "but", "for", "not", "you", "are", "was", "were", "this", "that", "with", "have", "has",
+    "had", "from", "they", "them", "what", "when", "where", "who", "why", "how", "all", "any", "can",
+    "will", "just", "there", "here", "then", "than", "your", "its", "our", "his", "her", "she", "him",
+    "это", "как", "так", "что", "чтобы", "его", "она", "они", "оно", "мне", "меня", "тебя", "тебе",
+    "вот", "все", "всё", "еще", "ещё", "уже", "или", "если", "когда", "где", "тут", "там", "нет",
+    "был", "была", "было", "были", "быть", "для", "при", "без", "над", "под", "только", "даже",
+    "тоже", "очень", "потом", "вас", "вам", "нас", "нам", "них", "ему", "ней", "кто", "чем",
+];
+
+// lowercase without the punctuation around, as the word is compared to stopwords
+fn normalize(word: &str) -> String {
+    word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase()
+}
+
+fn is_seed_candidate(word: &str) -> bool {
+    let normalized = normalize(word);
+    normalized.chars().count() >= MINIMUM_SEED_LENGTH
+        && !STOPWORDS.contains(&normalized.as_str())
+        && !word.starts_with('/')
+        && word != BEGIN
+        && word != END
+}
+
+// Picks one of the candidates with probability proportional to count^(1/temperature),
+// so temperature 1 follows the learned frequencies, higher values flatten them
+// and zero always takes the most frequent continuation
+pub fn choose_weighted(candidates: Vec<(Vec<String>, i64)>, temperature: f64) -> Option<Vec<String>> {
+    let max_count = candidates.iter().map(|(_, count)| cmp::max(*count, 1)).max()?;
+
+    if temperature <= 0.0 {
+        return candidates
+            .into_iter()
+            .find(|(_, count)| cmp::max(*count, 1) == max_count)
+            .map(|(lexems, _)| lexems);
+    }
+
+    // counted relative to the most frequent candidate, so powf never overflows
+    let weights = candidates.iter().map(|(_, count)| {
+        let ratio = cmp::max(*count, 1) as f64 / max_count as f64;
+        ratio.powf(1.0 / temperature)
+    });
+
+    match WeightedIndex::new(weights) {
+        Ok(distribution) => {
+            let index = distribution.sample(&mut thread_rng());
+            candidates.into_iter().nth(index).map(|(lexems, _)| lexems)
+        }
+        Err(e) => {
+            debug!("Can't build weights for candidates: {:?}", e);
+            candidates.into_iter().next().map(|(lexems, _)| lexems)
+        }
+    }
+}
+
+// Limits of a generated text, zero means no limit, though nothing is ever
+// longer than a telegram message
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub min_words: usize,
+    pub max_words: usize,
+    pub min_chars: usize,
+    pub max_chars: usize,
+}
+
+impl Limits {
+    fn max_words(&self) -> usize {
+        if self.max_words == 0 { usize::MAX } else { self.max_words }
+    }
+
+    fn max_chars(&self) -> usize {
+        if self.max_chars == 0 { MESSAGE_LIMIT_CHARS } else { cmp::min(self.max_chars, MESSAGE_LIMIT_CHARS) }
+    }
+
+    // false if no text can meet the limits, like more chars than a telegram message has
+    pub fn is_possible(&self) -> bool {
+        self.min_words <= self.max_words() && self.min_chars <= self.max_chars()
+    }
+
+    fn is_short(&self, words: usize, chars: usize) -> bool {
+        words < self.min_words || chars < self.min_chars
+    }
+
+    fn is_long(&self, words: usize, chars: usize) -> bool {
+        words > self.max_words() || chars > self.max_chars()
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        let (words, chars) = (text.split_whitespace().count(), text.chars().count());
+        !self.is_short(words, chars) && !self.is_long(words, chars)
+    }
+
+    // cuts the text after the last whole word within the maximums
+    pub fn truncate(&self, text: &str) -> String {
+        let mut result = String::new();
+        let mut chars = 0;
+        for word in text.split_whitespace().take(self.max_words()) {
+            let length = word.chars().count() + if result.is_empty() { 0 } else { 1 };
+            if chars + length > self.max_chars() {
+                break;
+            }
+            if !result.is_empty() {
+                result.push(' ');
+            }
+            result.push_str(word);
+            chars += length;
+        }
+
+        // a single word longer than the limit is cut anyway
+        if result.is_empty() {
+            return text.chars().take(self.max_chars()).collect();
+        }
+        result
+    }
+}
+
+// words and characters the chain will have once joined
+fn measure(lexems: &[String]) -> (usize, usize) {
+    let (words, chars) = lexems
+        .iter()
+        .filter(|s| !BEGIN.eq(*s) && !END.eq(*s))
+        .fold((0, 0), |(words, chars), s| (words + 1, chars + s.chars().count()));
+    (words, chars + words.saturating_sub(1))
+}
+
+// position in the n-gram where the phrase starts and agrees with it
+// as far as both of them go
+fn phrase_start(ngram: &[String], phrase: &[String]) -> Option<usize> {
+    (0..ngram.len()).find(|i| ngram[*i..].iter().zip(phrase).all(|(a, b)| a == b))
+}
+
+pub fn join_lexems(lexems: &[String]) -> String {
+    lexems
+        .iter()
+        .filter(|s| !BEGIN.eq(*s) && !END.eq(*s))
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Markov chain walker over a single lexeme table of any store
+pub struct Generator<'a> {
+    store: &'a dyn LexemeStore,
+    table: &'a TableName,
+    order: usize,
+    temperature: f64,
+    limits: Limits,
+    // zero allows copying whole messages
+    max_copied: usize,
+    source: SourceFilter,
+}
+
+impl<'a> Generator<'a> {
+    pub fn new(store: &'a dyn LexemeStore, table: &'a TableName, temperature: f64) -> Result<Generator<'a>> {
+        let order = store.table_order(table)?;
+        Ok(Generator { store, table, order, temperature, limits: Limits::default(), max_copied: 0, source: SourceFilter::any() })
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Generator<'a> {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_max_copied(mut self, max_copied: usize) -> Generator<'a> {
+        self.max_copied = max_copied;
+        self
+    }
+
+    // only n-grams of these sources are used
+    pub fn with_source(mut self, source: SourceFilter) -> Generator<'a> {
+        self.source = source;
+        self
+    }
+
+    // Length of the longest run of the text the table can only have taken from
+    // a single message: each word of it is the one continuation of the words
+    // before it and was seen once, so there was nothing to mix it with. Phrases
+    // learned from many messages aren't copied from any of them. Contexts
+    // already looked at are in `known`, attempts share it.
+    fn copied_run(&self, text: &str, known: &mut HashMap<Vec<String>, bool>) -> Result<usize> {
+        let words = text.split_whitespace().map(String::from).collect::<Vec<String>>();
+        let (mut longest, mut run) = (0, 0);
+        for i in self.order..words.len() {
+            let context = &words[i - self.order..i];
+            let copied = match known.get(context) {
+                Some(copied) => *copied,
+                None => {
+                    let candidates = self.store.right(self.table, context, &self.source)?;
+                    let copied = candidates.len() == 1 && candidates[0].1 == 1;
+                    known.insert(context.to_vec(), copied);
+                    copied
+                }
+            };
+            if copied {
+                run += 1;
+                longest = cmp::max(longest, run + self.order);
+            } else {
+                run = 0;
+            }
+        }
+        Ok(longest)
+    }
+
+    // Texts out of the limits or copying too much of a message are generated
+    // again, if none is good the best is one fitting the limits and then the
+    // least copying one. Tells whether the text fits the limits.
+    fn best_attempt<F>(&self, generate: F) -> Result<(String, bool)>
+    where
+        F: Fn() -> Result<String>,
+    {
+        let mut best: Option<(bool, usize, String)> = None;
+        let mut known = HashMap::new();
+        for attempt in 0..MAXIMUM_ATTEMPTS {
+            let result = generate()?;
+            if result == NOT_FOUND {
+                return Ok((result, true));
+            }
+
+            let copied = if self.max_copied == 0 { 0 } else { self.copied_run(&result, &mut known)? };
+            let fits = self.limits.fits(&result);
+            if fits && copied <= self.max_copied {
+                return Ok((result, true));
+            }
+            trace!("attempt {} copies {} words, limits {:?}: {}", attempt, copied, self.limits, result);
+
+            if best.as_ref().map_or(true, |(best_fits, least, _)| (fits, cmp::Reverse(copied)) > (*best_fits, cmp::Reverse(*least))) {
+                best = Some((fits, copied, result));
+            }
+        }
+
+        let (fits, copied, result) = best.unwrap_or_default();
+        debug!("Nothing acceptable generated, using one copying {} words", copied);
+        Ok((result, fits))
+    }
+
+    // the best attempt, truncated to the maximums if it's too long
+    fn acceptable<F>(&self, generate: F) -> Result<String>
+    where
+        F: Fn() -> Result<String>,
+    {
+        let (result, fits) = self.best_attempt(generate)?;
+        Ok(if fits { result } else { self.limits.truncate(&result) })
+    }
+
+    pub fn select(&self, input: &str) -> Result<String> {
+        self.acceptable(|| self.select_once(input))
+    }
+
+    fn select_once(&self, input: &str) -> Result<String> {
+        let words = input.split_whitespace().map(String::from).collect::<Vec<String>>();
+
+        match words.len() {
+            0 => {
+                let result = self.select_random()?;
+                debug!("Found by random: {}", result);
+                Ok(result)
+            }
+            1 => {
+                let result = self.select_lexeme(&words[0])?;
+                debug!("Found by word {}: {}", words[0], result);
+                Ok(result)
+            }
+            _ => {
+                let result = self.select_phrase(&words)?;
+                debug!("Found by phrase {:?}: {}", words, result);
+                Ok(result)
+            }
+        }
+    }
+
+    // Answers a message with a chain around its rarest word the table knows,
+    // a random chain is the answer only when no word of the message is known
+    pub fn reply(&self, text: &str) -> Result<String> {
+        match self.seed(text)? {
+            Some(word) => {
+                let result = self.acceptable(|| self.select_lexeme(&word))?;
+                debug!("Replied around {}: {}", word, result);
+                Ok(result)
+            }
+            None => self.select(""),
+        }
+    }
+
+    fn seed(&self, text: &str) -> Result<Option<String>> {
+        let mut candidates: Vec<&str> = Vec::new();
+        for word in text.split_whitespace().filter(|word| is_seed_candidate(word)) {
+            if !candidates.contains(&word) {
+                candidates.push(word);
+            }
+        }
+
+        let mut seed: Option<(i64, &str)> = None;
+        for word in candidates.into_iter().take(MAXIMUM_SEED_CANDIDATES) {
+            let count = self.store.count(self.table, word, &self.source)?;
+            trace!("seed candidate {} is known {} times", word, count);
+            if count > 0 && seed.map_or(true, |(rarest, _)| count < rarest) {
+                seed = Some((count, word));
+            }
+        }
+        Ok(seed.map(|(_, word)| String::from(word)))
+    }
+
+    fn select_lexeme(&self, word: &str) -> Result<String> {
+        match choose_weighted(self.store.lexeme(self.table, word, &self.source)?, self.temperature) {
+            Some(mut lexems) => {
+                self.select_left(&mut lexems)?;
+                self.select_right(&mut lexems)?;
+                Ok(join_lexems(&lexems))
+            }
+            None => Ok(String::from(NOT_FOUND)),
+        }
+    }
+
+    // A chain containing the whole phrase: it starts from an n-gram where the
+    // phrase begins and agrees with it up to the n-gram's end, then the rest
+    // of the phrase is forced word by word, and only then the chain grows freely
+    fn select_phrase(&self, phrase: &[String]) -> Result<String> {
+        let mut candidates = self
+            .store
+            .lexeme(self.table, &phrase[0], &self.source)?
+            .into_iter()
+            .filter(|(ngram, _)| phrase_start(ngram, phrase).is_some())
+            .collect::<Vec<(Vec<String>, i64)>>();
+
+        for _ in 0..MAXIMUM_PHRASE_ATTEMPTS {
+            let mut lexems = match choose_weighted(candidates.clone(), self.temperature) {
+                Some(lexems) => lexems,
+                None => break,
+            };
+
+            // the phrase always starts somewhere in a candidate
+            let covered = lexems.len() - phrase_start(&lexems, phrase).unwrap();
+            if self.force_right(&mut lexems, &phrase[cmp::min(covered, phrase.len())..])? {
+                self.select_left(&mut lexems)?;
+                self.select_right(&mut lexems)?;
+                return Ok(join_lexems(&lexems));
+            }
+
+            trace!("phrase doesn't go on after {:?}", lexems);
+            candidates.retain(|(ngram, _)| !lexems.starts_with(ngram));
+        }
+        Ok(String::from(NOT_FOUND))
+    }
+
+    // A whole sentence passing through both words in either order, none if
+    // the words aren't connected closely enough in the table or no sentence
+    // fits the limits, truncating could cut off one of the words
+    pub fn bridge(&self, first: &str, second: &str) -> Result<Option<String>> {
+        let mut path = self.find_path(first, second)?;
+        if path.is_none() {
+            path = self.find_path(second, first)?;
+        }
+
+        match path {
+            Some(path) => {
+                let (result, fits) = self.best_attempt(|| {
+                    let mut lexems = path.clone();
+                    self.select_left(&mut lexems)?;
+                    self.select_right(&mut lexems)?;
+                    Ok(join_lexems(&lexems))
+                })?;
+                if !fits {
+                    debug!("No bridge of {} and {} within {:?}", first, second, self.limits);
+                    return Ok(None);
+                }
+                debug!("Bridged {} and {}: {}", first, second, result);
+                Ok(Some(result))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Breadth first search from every n-gram with `from` towards a chain where
+    // `to` comes after it, the chain is extended to the right only
+    fn find_path(&self, from: &str, to: &str) -> Result<Option<Vec<String>>> {
+        let has_path = |lexems: &[String]| {
+            lexems.iter().position(|w| w == from).map_or(false, |i| lexems[i + 1..].iter().any(|w| w == to))
+        };
+
+        let mut starts = self.store.lexeme(self.table, from, &self.source)?;
+        starts.shuffle(&mut thread_rng());
+
+        let mut queue = VecDeque::new();
+        let mut visited = HashSet::new();
+        for (ngram, _) in starts {
+            if has_path(&ngram) {
+                return Ok(Some(ngram));
+            }
+            queue.push_back((ngram, 0));
+        }
+
+        while let Some((lexems, length)) = queue.pop_front() {
+            let context = lexems[lexems.len() - self.order..].to_vec();
+            if length >= MAXIMUM_BRIDGE_LENGTH || END.eq(&context[context.len() - 1]) || !visited.insert(context.clone()) {
+                continue;
+            }
+            if visited.len() > MAXIMUM_BRIDGE_CONTEXTS {
+                debug!("Gave up bridging {} and {} after {} contexts", from, to, visited.len());
+                break;
+            }
+
+            let mut next = self.store.right(self.table, &context, &self.source)?;
+            next.shuffle(&mut thread_rng());
+            for (mut ngram, _) in next {
+                let mut path = lexems.clone();
+                path.push(ngram.pop().unwrap());
+                if has_path(&path) {
+                    return Ok(Some(path));
+                }
+                queue.push_back((path, length + 1));
+            }
+        }
+        Ok(None)
+    }
+
+    // appends exactly these words to the chain, false if the table has no such continuation
+    fn force_right(&self, lexems: &mut Vec<String>, words: &[String]) -> Result<bool> {
+        for word in words {
+            let context = lexems[lexems.len() - self.order..].to_vec();
+            let known = self
+                .store
+                .right(self.table, &context, &self.source)?
+                .iter()
+                .any(|(ngram, _)| ngram.last() == Some(word));
+            if !known {
+                return Ok(false);
+            }
+            lexems.push(word.clone());
+        }
+        Ok(true)
+    }
+
+    fn select_random(&self) -> Result<String> {
+        // #beg# is always first
+        match choose_weighted(self.store.begin(self.table, &self.source)?, self.temperature) {
+            Some(mut lexems) => {
+                self.select_right(&mut lexems)?;
+                Ok(join_lexems(&lexems))
+            }
+            None => Ok(String::from(NOT_FOUND)),
+        }
+    }
+
+    // Keeps the chain within the limits as far as the table allows: continuations
+    // with the marker are avoided while the chain is too short and preferred once
+    // it's nearly full. The left side grows first and gets half of the maximums.
+    fn steer(&self, candidates: Vec<(Vec<String>, i64)>, lexems: &[String], marker: &str, share: usize) -> Vec<(Vec<String>, i64)> {
+        let (words, chars) = measure(lexems);
+        let (ending, going): (Vec<_>, Vec<_>) = candidates
+            .into_iter()
+            .partition(|(ngram, _)| ngram.iter().any(|w| w == marker));
+
+        let nearly_full = words + 1 >= self.limits.max_words() / share
+            || chars >= self.limits.max_chars() * 9 / 10 / share;
+
+        if self.limits.is_short(words, chars) && !going.is_empty() {
+            going
+        } else if nearly_full && !ending.is_empty() {
+            ending
+        } else {
+            going.into_iter().chain(ending).collect()
+        }
+    }
+
+    // Both of them grow the chain in place until a marker, the maximum length or
+    // the recursion limit is reached, the context is the first or the last
+    // `order` words of the chain
+    fn select_left(&self, lexems: &mut Vec<String>) -> Result<()> {
+        // the chain is kept reversed while growing, so new words are pushed to the back
+        lexems.reverse();
+
+        let mut recursion = 0;
+        while !BEGIN.eq(&lexems[lexems.len() - 1]) && recursion < MAXIMUM_RECURSION_DEPTH {
+            let context = lexems.iter().rev().take(self.order).cloned().collect::<Vec<String>>();
+            trace!("left context {:?}", context);
+
+            let candidates = self.steer(self.store.left(self.table, &context, &self.source)?, lexems, BEGIN, 2);
+            match choose_weighted(candidates, self.temperature) {
+                Some(ngram) => lexems.push(ngram[0].clone()),
+                None => break,
+            }
+
+            let (words, chars) = measure(lexems);
+            if self.limits.is_long(words, chars) {
+                break;
+            }
+            recursion = recursion + 1;
+        }
+
+        lexems.reverse();
+        Ok(())
+    }
+
+    fn select_right(&self, lexems: &mut Vec<String>) -> Result<()> {
+        let mut recursion = 0;
+        while !END.eq(&lexems[lexems.len() - 1]) && recursion < MAXIMUM_RECURSION_DEPTH {
+            let context = lexems[lexems.len() - self.order..].to_vec();
+            trace!("right context {:?}", context);
+
+            let candidates = self.steer(self.store.right(self.table, &context, &self.source)?, lexems, END, 1);
+            match choose_weighted(candidates, self.temperature) {
+                Some(mut ngram) => lexems.push(ngram.pop().unwrap()),
+                None => break,
+            }
+
+            let (words, chars) = measure(lexems);
+            if self.limits.is_long(words, chars) {
+                break;
+            }
+            recursion = recursion + 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    fn store(texts: &[&str]) -> (MemoryStore, TableName) {
+        let store = MemoryStore::new();
+        let table = TableName::new("scratch_test").unwrap();
+        store.create_lexeme_table(&table, DEFAULT_ORDER).unwrap();
+        let batch = store.open_batch(&table, &Source::new("1", SourceKind::Document)).unwrap();
+        store.insert_many(&batch, texts, "1").unwrap();
+        (store, table)
+    }
+
+    #[test]
+    fn words_seen_once_are_copied() {
+        let (store, table) = store(&["one two three four five"]);
+        let generator = Generator::new(&store, &table, DEFAULT_TEMPERATURE).unwrap();
+        assert_eq!(generator.copied_run("one two three four five", &mut HashMap::new()).unwrap(), 5);
+    }
+
+    #[test]
+    fn common_phrases_are_not_copied() {
+        let (store, table) = store(&["one two three four five", "one two three four five", "one two six"]);
+        let generator = Generator::new(&store, &table, DEFAULT_TEMPERATURE).unwrap();
+        let mut known = HashMap::new();
+        assert_eq!(generator.copied_run("one two three four five", &mut known).unwrap(), 0);
+        assert_eq!(known.len(), 3);
+    }
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    const BRIDGED: &[&str] = &["cats chase small mice", "small mice eat cheese", "dogs bark loudly"];
+
+    #[test]
+    fn words_are_bridged_in_either_order() {
+        let (store, table) = store(BRIDGED);
+        let generator = Generator::new(&store, &table, DEFAULT_TEMPERATURE).unwrap();
+        let sentence = Some(String::from("cats chase small mice eat cheese"));
+        assert_eq!(generator.bridge("cats", "cheese").unwrap(), sentence);
+        assert_eq!(generator.bridge("cheese", "cats").unwrap(), sentence);
+        assert_eq!(generator.find_path("cheese", "cats").unwrap(), None);
+    }
+
+    #[test]
+    fn unconnected_words_have_no_bridge() {
+        let (store, table) = store(BRIDGED);
+        let generator = Generator::new(&store, &table, DEFAULT_TEMPERATURE).unwrap();
+        assert_eq!(generator.bridge("dogs", "cheese").unwrap(), None);
+        assert_eq!(generator.bridge("cats", "unknown").unwrap(), None);
+
+        // the only sentence is longer than the limits and can't be cut
+        let limited = generator.with_limits(Limits { max_words: 3, ..Limits::default() });
+        assert_eq!(limited.bridge("cats", "cheese").unwrap(), None);
+    }
+
+    #[test]
+    fn phrases_longer_than_an_ngram_are_kept_whole() {
+        let (store, table) = store(BRIDGED);
+        let generator = Generator::new(&store, &table, DEFAULT_TEMPERATURE).unwrap();
+        assert_eq!(generator.select_phrase(&words("chase small mice eat")).unwrap(), "cats chase small mice eat cheese");
+        assert_eq!(generator.select_phrase(&words("small mice bark")).unwrap(), NOT_FOUND);
+    }
+
+    #[test]
+    fn only_known_continuations_are_forced() {
+        let (store, table) = store(BRIDGED);
+        let generator = Generator::new(&store, &table, DEFAULT_TEMPERATURE).unwrap();
+        let mut lexems = words("#beg# cats chase");
+        assert!(generator.force_right(&mut lexems, &words("small mice eat")).unwrap());
+        assert_eq!(lexems, words("#beg# cats chase small mice eat"));
+        assert!(!generator.force_right(&mut lexems, &words("bark")).unwrap());
+    }
+
+    #[test]
+    fn limits_beyond_a_message_are_impossible() {
+        assert!(Limits::default().is_possible());
+        assert!(Limits { min_chars: 4000, ..Limits::default() }.is_possible());
+        assert!(!Limits { min_chars: 5000, max_chars: 6000, ..Limits::default() }.is_possible());
+        assert!(!Limits { min_chars: 200, max_chars: 100, ..Limits::default() }.is_possible());
+        assert!(!Limits { min_words: 3, max_words: 2, ..Limits::default() }.is_possible());
+    }
+}
@@ -1,28 +1,283 @@
 use log4rs;
-use log::{info, trace};
+use log::{error, info, warn};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::env;
+use std::time::Duration;
+mod error;
 mod sqlite;
+mod migrations;
+mod memory;
+mod store;
+mod generator;
 mod cmd;
+mod commands;
 mod telegram;
 mod user_management;
 mod user;
-use cmd::CommandType;
+mod export;
+mod document;
+use cmd::{CommandParser, ParsedCommand, Permission};
+use commands::{Context, COMMANDS};
+use document::{Contents, Splitting};
+use error::Result;
+use telegram::{IncomingDocument, IncomingMessage, TelegramActions};
 use telegram::TelegramActions::*;
-use sqlite::SqliteDB;
+use sqlite::{SqliteDB, SqliteConn};
+use memory::MemoryStore;
+use store::{LexemeStore, Source, SourceKind, TableName, DEFAULT_ORDER};
+use user::UserAccount;
 use user_management::UserManager;
 use lazy_static::*;
+use telegram_bot::{ChatId, UserId};
 
 lazy_static! {
     static ref SQLITE_POOL: SqliteDB = {
         let dbpath = env::var("DATABASE_PATH").expect("DATABASE_PATH is not provided");
-        sqlite::SqliteDB::new(&dbpath)
+        sqlite::SqliteDB::new(&dbpath).unwrap_or_else(|e| panic!("Can't prepare database {}: {}", dbpath, e))
     };
 
+    static ref SCRATCH_STORE: MemoryStore = MemoryStore::new();
+
     static ref USER_MANAGER: UserManager = {
-        user_management::UserManager::new(&mut SQLITE_POOL.get_conn())
+        SQLITE_POOL.get_conn()
+            .and_then(|sqlite| user_management::UserManager::new(&sqlite))
+            .unwrap_or_else(|e| panic!("Can't load user profiles: {}", e))
     };
 }
 
+// scratch tables never touch the disk
+fn lexeme_store<'a>(table: &TableName, sqlite: &'a SqliteConn) -> &'a dyn LexemeStore {
+    if table.is_scratch() {
+        &*SCRATCH_STORE
+    } else {
+        sqlite
+    }
+}
+
+// The table of the chat. Scratch tables are gone after a restart while chats
+// still point at them, such a table comes back empty and of the default order.
+fn chat_table(user_account: &UserAccount) -> Result<TableName> {
+    let table = TableName::new(&user_account.lexeme_table).unwrap_or_default();
+    if table.is_scratch() {
+        SCRATCH_STORE.create_lexeme_table(&table, DEFAULT_ORDER)?;
+    }
+    Ok(table)
+}
+
+// failures are logged and answered instead of taking the whole task down
+fn reply_on_error(chat_id: ChatId, result: Result<TelegramActions>) -> TelegramActions {
+    result.unwrap_or_else(|e| {
+        error!("ChatId <{}>: {}", chat_id, e);
+        ReplyToMessage(e.reply())
+    })
+}
+
+// Users listed in ADMIN_USERS are made admins on every start, everybody
+// else gets there only by /grant
+fn bootstrap_admins() -> Result<()> {
+    let admins = env::var("ADMIN_USERS").unwrap_or_default();
+    let sqlite = SQLITE_POOL.get_conn()?;
+
+    for admin in admins.split(',').map(str::trim).filter(|admin| !admin.is_empty()) {
+        match admin.parse::<i64>() {
+            Ok(_) => {
+                info!("Bootstrapping admin {}", admin);
+                USER_MANAGER.set_admin(&sqlite, admin, true)?;
+            },
+            Err(_) => warn!("Skipping admin {:?}, it's not a telegram user id", admin),
+        }
+    }
+    Ok(())
+}
+
+type BotCommandParser = CommandParser<Context, Result<TelegramActions>>;
+
+// what the bot knows about itself
+struct Bot {
+    id: UserId,
+    username: Option<String>,
+    parser: BotCommandParser,
+}
+
+impl Bot {
+    fn is_addressed(&self, message: &IncomingMessage) -> bool {
+        let mentioned = self.username.as_ref().map_or(false, |username| {
+            message.text.to_lowercase().contains(&format!("@{}", username.to_lowercase()))
+        });
+        mentioned || message.reply_to == Some(self.id)
+    }
+}
+
+// Mentions, replies to the bot and triggers are always answered, anything
+// else only by chance and never in mention only chats. The cooldown is for all.
+fn should_answer(bot: &Bot, user: &UserAccount, message: &IncomingMessage) -> bool {
+    if !user.answer_mode {
+        return false
+    }
+
+    let triggered = message
+        .text
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .any(|word| user.triggers.contains(&word));
+    let wanted = bot.is_addressed(message)
+        || triggered
+        || (!user.mention_only && rand::random::<f64>() < user.reply_probability);
+
+    wanted && USER_MANAGER.try_answer(user)
+}
+
+fn context(message: &IncomingMessage) -> Result<Context> {
+    let sqlite = SQLITE_POOL.get_conn()?;
+    let user_account = USER_MANAGER.get_user(&sqlite, &message.chat_id.to_string())?;
+    let table_name = chat_table(&user_account)?;
+
+    info!("User account is {:?}", user_account);
+    info!("ChatId <{}>: input txt {:?}", message.chat_id, message.text);
+    Ok(Context { user_id: message.user_id, sqlite, user_account, table_name })
+}
+
+fn handle_message(bot: &Bot, message: &IncomingMessage) -> Result<TelegramActions> {
+    let (command, args) = match bot.parser.parse_command(&message.text) {
+        ParsedCommand::EForeignCommand => return Ok(NoReply),
+        ParsedCommand::EWrongUsage(command) => return Ok(ReplyToMessage(format!("Usage: {}", command.usage()))),
+        ParsedCommand::ECommand(command, args) => (command, args),
+        // nobody asked for anything, so failures only go to the log
+        ParsedCommand::ENoCommand => return Ok(handle_chat_message(bot, message).unwrap_or_else(|e| {
+            error!("ChatId <{}>: {}", message.chat_id, e);
+            NoReply
+        })),
+    };
+
+    if command.permission == Permission::Admin && !USER_MANAGER.is_admin(&message.user_id.to_string()) {
+        info!("User {} is not an admin, refusing {}", message.user_id, command.name);
+        return Ok(ReplyToMessage(String::from("This command is for admins only")))
+    }
+
+    let mut ctx = context(message)?;
+    (command.handler)(&mut ctx, &args)
+}
+
+fn handle_chat_message(bot: &Bot, message: &IncomingMessage) -> Result<TelegramActions> {
+    let (chat_id, user_id, input) = (message.chat_id, message.user_id, &message.text);
+    let ctx = context(message)?;
+
+    // the answer comes first, otherwise every word of the message is
+    // already known and the rarest of them just echo the message back
+    let action = if should_answer(bot, &ctx.user_account, message) {
+        let generator = ctx.generator()?;
+        if ctx.user_account.seeded_replies {
+            ReplyToChat(generator.reply(input)?)
+        } else {
+            ReplyToChat(generator.select("")?)
+        }
+    } else {
+        NoReply
+    };
+
+    if ctx.user_account.learn_mode {
+        let source = Source::new(&chat_id.to_string(), SourceKind::Chat);
+        let batch = ctx.store().open_batch(&ctx.table_name, &source)?;
+        ctx.store().insert(&batch, input, &user_id.to_string())?;
+    }
+    Ok(action)
+}
+
+// The caption of a document names the table to learn it into, the current
+// table of the chat by default. Chat exports may also have any number of
+// `from=NAME` to learn messages of these authors only, csv files `column=NAME`
+// to learn this column, and texts `split=sentences` to be learned by sentences
+// rather than by lines
+#[derive(Default)]
+struct Caption {
+    table_name: Option<TableName>,
+    authors: Vec<String>,
+    column: Option<String>,
+    splitting: Splitting,
+}
+
+fn parse_caption(caption: &str) -> Option<Caption> {
+    let mut parsed = Caption::default();
+    for token in cmd::tokenize(caption) {
+        if let Some(author) = token.strip_prefix(export::AUTHOR_PREFIX) {
+            parsed.authors.push(String::from(author));
+        } else if let Some(column) = token.strip_prefix(document::COLUMN_PREFIX) {
+            parsed.column = Some(String::from(column));
+        } else if let Some(splitting) = token.strip_prefix(document::SPLIT_PREFIX) {
+            parsed.splitting = Splitting::parse(splitting)?;
+        } else if parsed.table_name.is_none() {
+            parsed.table_name = Some(TableName::new(&token)?);
+        } else {
+            return None
+        }
+    }
+    Some(parsed)
+}
+
+fn handle_file(document: &IncomingDocument) -> Result<TelegramActions> {
+    // only admins get here, see main
+    let (chat_id, user_id) = (document.chat_id, document.user_id);
+    let caption = match parse_caption(document.caption.as_deref().unwrap_or_default()) {
+        Some(caption) => caption,
+        None => return Ok(ReplyToMessage(String::from(
+            "The caption should be a table name or nothing, chat exports may also have from=NAME, \
+             csv files column=NAME and texts split=lines or split=sentences"
+        ))),
+    };
+    let contents = document::read(&document.file_name, &document.data, caption.column.as_deref())?;
+
+    // messages of an export are taught by their authors, so /forget works for them
+    // as well, the uploader is left with the rest
+    let uploader = user_id.to_string();
+    let exports = contents
+        .iter()
+        .filter_map(|contents| match contents {
+            Contents::Export(json) => Some(export::messages(json, &caption.authors)),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let uploaded = contents
+        .iter()
+        .flat_map(|contents| match contents {
+            Contents::Text(text) => caption.splitting.split(text),
+            Contents::Cells(cells) => cells.iter().map(|cell| Cow::from(cell.as_str())).collect(),
+            Contents::Export(_) => Vec::new(),
+        })
+        .collect::<Vec<Cow<'_, str>>>();
+    let mut texts: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for message in exports.iter().flatten() {
+        let author = message.user_id.as_deref().unwrap_or(&uploader);
+        texts.entry(author).or_insert_with(Vec::new).push(&message.text);
+    }
+    if !uploaded.is_empty() {
+        texts.entry(&uploader).or_insert_with(Vec::new).extend(uploaded.iter().map(|text| text.as_ref()));
+    }
+
+    let sqlite = SQLITE_POOL.get_conn()?;
+    let user_account = USER_MANAGER.get_user(&sqlite, &chat_id.to_string())?;
+    let table_name = match caption.table_name {
+        Some(table_name) => table_name,
+        None => chat_table(&user_account)?,
+    };
+
+    info!("ChatId <{}>: learning {} of {} bytes into {}", chat_id, document.file_name, document.data.len(), table_name);
+    let store = lexeme_store(&table_name, &sqlite);
+    let source = Source::new(&chat_id.to_string(), SourceKind::Document);
+    let batch = store.open_batch(&table_name, &source)?;
+    let (mut learned, mut ngrams) = (0, 0);
+    for (author, texts) in &texts {
+        let (author_learned, author_ngrams) = store.insert_many(&batch, texts, author)?;
+        learned += author_learned;
+        ngrams += author_ngrams;
+    }
+
+    Ok(ReplyToMessage(format!(
+        "Learned {} messages, {} n-grams into {} as batch {}, /undo {} {} takes it back",
+        learned, ngrams, table_name, batch.id, batch.id, table_name
+    )))
+}
+
 #[tokio::main(threaded_scheduler, core_threads = 4, max_threads = 8)]
 async fn main() {
     let logconfig = env::var("LOG_CONFIG").expect("LOG_CONFIG is not provided");
@@ -34,80 +289,33 @@ async fn main() {
 
     let telegram = telegram::Telegram::new(&token);
 
-    loop {
-        telegram.serve(|chat_id, input| {
-            let mut sqlite = SQLITE_POOL.get_conn();
-            let mut user_account = USER_MANAGER.get_user(&sqlite, &chat_id.to_string());
-            let cmdtype = cmd::CommandParser::parse_command(&input);
-            let table_name = &user_account.lexeme_table;
-
-            info!("User account is {:?}", user_account);
-            info!("ChatId <{}>: input txt {:?}", chat_id, &input);
-
-            match cmdtype {
-                CommandType::EGenerateByWord(s) => ReplyToMessage(sqlite.select(&table_name, s)),
-                CommandType::EGetCountByWord(s) => {
-                    if let Some(n) = sqlite.is_exist(&table_name, s) {
-                        ReplyToMessage(format!("Count {}", n))
-                    } else {
-                        ReplyToMessage(format!("Empty word provided"))
-                    }
-                }
-                CommandType::EDisableForChat => {
-                    info!("Disable bot for this chat {}", &user_account.user_id);
-                    user_account.answer_mode = false;
-                    USER_MANAGER.update_user(&sqlite, &user_account);
-                    NoReply
-                },
-                CommandType::EEnableForChat => {
-                    info!("Enable bot for this chat {}", &user_account.user_id);
-                    user_account.answer_mode = true;
-                    USER_MANAGER.update_user(&sqlite, &user_account);
-                    NoReply
-                },
-                CommandType::ENoCommand => {
-                    sqlite.insert(&table_name, input);
-                    if user_account.answer_mode {
-                        ReplyToChat(sqlite.select(&table_name, String::new()))
-                    } else {
-                        NoReply
-                    }
-                },
-                CommandType::EChangeLexemeTable(table) => {
-                    sqlite.create_lexeme_table(&table);
-                    {
-                        user_account.lexeme_table;
-                    }
-                    user_account.lexeme_table = String::from(&table);
-                    USER_MANAGER.update_user(&sqlite, &user_account);
-                    ReplyToMessage(format!("Created table {}", &table))
-                },
-                CommandType::EGetLexemeTable => {
-                    ReplyToMessage(format!("Your current lexeme table is: {}", &user_account.lexeme_table))
-                },
-                CommandType::EHelpCommand => {
-                    ReplyToMessage(format!("JelezyakaBot 2.0:\n/q - query funny story this awesome bot :))))\n/on - enable answer mode for this room/chat\n/off - disable answer mode for this room/chat\n/count - count word in your lexeme table\n/help - this help\n"))
-                }, 
-                CommandType::EAdminHelpCommand => {
-                    ReplyToMessage(format!("EBALO AUF NUL!\n/adminhelp - only if you're admin of this bot\n/changetable - change lexeme table for this room/chat\n/getcurrenttable - get current table for this room/chat\n/listtable - list of lexeme tables\n"))
-                },
-                CommandType::EListLexemeTables => {
-                    ReplyToMessage(format!("List of lexeme tables - {}", sqlite.fetch_lexems_tables_list().join(",")))
-                },
-                _ => { NoReply }
+    let me = loop {
+        match telegram.me().await {
+            Ok(me) => break me,
+            Err(e) => {
+                error!("Can't get the bot account: {}", e);
+                tokio::time::delay_for(Duration::from_secs(5)).await;
             }
-        },
-        |chat_id, input_text| {
-            let sqlite = SQLITE_POOL.get_conn();
-            info!("input txt {}", &input_text);
-            let user_account = USER_MANAGER.get_user(&sqlite, &chat_id.to_string());
-            let table_name = String::from("default");
-
-            info!("inserting... {:?}", user_account);
-            if user_account.is_admin {
-                trace!("inserting the text into db");
-                sqlite.insert(&table_name, input_text);
-            }
-        }).await;
+        }
+    };
+    bootstrap_admins().unwrap_or_else(|e| panic!("Can't bootstrap admins: {}", e));
+
+    // lives as long as the bot does, and handlers have to stay Copy
+    let bot: &'static Bot = Box::leak(Box::new(Bot {
+        id: me.id,
+        username: me.username.clone(),
+        parser: CommandParser::new(me.username, COMMANDS),
+    }));
+
+    if let Err(e) = telegram.set_commands(&cmd::menu(COMMANDS)).await {
+        warn!("Can't set the commands menu: {}", e);
+    }
+
+    loop {
+        telegram.serve(
+            move |message| reply_on_error(message.chat_id, handle_message(bot, &message)),
+            |document| reply_on_error(document.chat_id, handle_file(&document)),
+            |user_id| USER_MANAGER.is_admin(&user_id.to_string()),
+        ).await;
     }
 }
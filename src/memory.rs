@@ -0,0 +1,360 @@
+use crate::error::{Error, Result};
+use crate::store::*;
+use crate::user::*;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+struct MemoryTable {
+    order: usize,
+    // one entry for every source of the n-gram
+    ngrams: HashMap<Vec<String>, Vec<Provenance>>,
+}
+
+impl MemoryTable {
+    fn filter<F>(&self, predicate: F, source: &SourceFilter) -> Vec<(Vec<String>, i64)>
+    where
+        F: Fn(&[String]) -> bool,
+    {
+        self.ngrams
+            .iter()
+            .filter(|(ngram, _)| predicate(ngram))
+            .filter_map(|(ngram, sources)| {
+                let matching = sources.iter().filter(|p| source.matches(&p.source)).collect::<Vec<&Provenance>>();
+                if matching.is_empty() {
+                    None
+                } else {
+                    Some((ngram.clone(), matching.iter().map(|p| p.count).sum()))
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    tables: HashMap<TableName, MemoryTable>,
+    users: HashMap<String, UserAccount>,
+    // batch ids are positions here plus one
+    batches: Vec<Batch>,
+    // how many times a user taught every n-gram in a batch
+    contributions: HashMap<(String, i64), HashMap<Vec<String>, i64>>,
+}
+
+impl MemoryState {
+    // takes the chosen contributions out of their tables
+    fn subtract<F>(&mut self, chosen: F) -> Forgotten
+    where
+        F: Fn(&str, i64) -> bool,
+    {
+        let keys = self
+            .contributions
+            .keys()
+            .filter(|(user, batch)| chosen(user, *batch))
+            .cloned()
+            .collect::<Vec<(String, i64)>>();
+
+        let mut taken: HashMap<(TableName, Vec<String>, Source), i64> = HashMap::new();
+        for key in keys {
+            let ngrams = self.contributions.remove(&key).unwrap_or_default();
+            if let Some(batch) = self.batches.iter().find(|batch| batch.id == key.1) {
+                for (ngram, count) in ngrams {
+                    *taken.entry((batch.table.clone(), ngram, batch.source.clone())).or_insert(0) += count;
+                }
+            }
+        }
+
+        let mut forgotten = Forgotten::default();
+        for ((table, ngram, source), count) in taken {
+            let t = match self.tables.get_mut(&table) {
+                Some(t) => t,
+                None => continue,
+            };
+            let sources = t.ngrams.entry(ngram.clone()).or_insert_with(Vec::new);
+            if let Some(provenance) = sources.iter_mut().find(|p| p.source == source) {
+                provenance.count -= count;
+                forgotten.ngrams += 1;
+                forgotten.count += count;
+                if provenance.count <= 0 {
+                    forgotten.removed += 1;
+                }
+            }
+
+            sources.retain(|p| p.count > 0);
+            if sources.is_empty() {
+                t.ngrams.remove(&ngram);
+            }
+        }
+        forgotten
+    }
+}
+
+// Keeps everything in hashmaps, so it's good for tests and for scratch tables
+// nobody wants to see on disk. Lookups are plain scans over the whole table.
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        info!("MemoryStore starting");
+        MemoryStore { state: Mutex::new(MemoryState::default()) }
+    }
+
+    fn with_table<T, F>(&self, table: &TableName, f: F) -> Result<T>
+    where
+        F: Fn(&MemoryTable) -> T,
+    {
+        let state = self.state.lock().unwrap();
+        match state.tables.get(table) {
+            Some(t) => Ok(f(t)),
+            None => Err(Error::TableNotFound(String::from(table.as_str()))),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl LexemeStore for MemoryStore {
+    fn create_lexeme_table(&self, table: &TableName, order: usize) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.tables.entry(table.clone()).or_insert_with(|| {
+            info!("Created a new table '{}' in memory", table);
+            MemoryTable { order, ngrams: HashMap::new() }
+        });
+        Ok(entry.order)
+    }
+
+    fn table_order(&self, table: &TableName) -> Result<usize> {
+        let state = self.state.lock().unwrap();
+        match state.tables.get(table) {
+            Some(t) => Ok(t.order),
+            None => Err(Error::TableNotFound(String::from(table.as_str()))),
+        }
+    }
+
+    fn fetch_lexems_tables_list(&self) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let mut tables: Vec<String> = state.tables.keys().map(|t| String::from(t.as_str())).collect();
+        tables.sort();
+        Ok(tables)
+    }
+
+    fn reindex(&self, table: &TableName) -> Result<()> {
+        debug!("Nothing to reindex in memory for {}", table);
+        Ok(())
+    }
+
+    fn insert_ngrams(&self, batch: &Batch, ngrams: &[Vec<String>], user: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let MemoryState { tables, contributions, .. } = &mut *state;
+        let (table, source) = (&batch.table, &batch.source);
+        let now = unix_time();
+        match tables.get_mut(table) {
+            Some(t) => {
+                let contributed = contributions.entry((String::from(user), batch.id)).or_insert_with(HashMap::new);
+                let length = t.order + 1;
+                for ngram in ngrams.iter().filter(|ngram| ngram.len() == length) {
+                    let sources = t.ngrams.entry(ngram.clone()).or_insert_with(Vec::new);
+                    match sources.iter_mut().find(|p| p.source == *source) {
+                        Some(provenance) => {
+                            provenance.count += 1;
+                            provenance.last_seen = now;
+                        }
+                        None => sources.push(Provenance { source: source.clone(), count: 1, first_seen: now, last_seen: now }),
+                    }
+                    *contributed.entry(ngram.clone()).or_insert(0) += 1;
+                }
+                Ok(())
+            }
+            None => Err(Error::TableNotFound(String::from(table.as_str()))),
+        }
+    }
+
+    fn count(&self, table: &TableName, word: &str, filter: &SourceFilter) -> Result<i64> {
+        Ok(self.lexeme(table, word, filter)?.iter().map(|(_, count)| count).sum())
+    }
+
+    fn right(&self, table: &TableName, context: &[String], filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        self.with_table(table, |t| t.filter(|ngram| ngram.starts_with(context), filter))
+    }
+
+    fn left(&self, table: &TableName, context: &[String], filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        self.with_table(table, |t| t.filter(|ngram| ngram.ends_with(context), filter))
+    }
+
+    fn lexeme(&self, table: &TableName, word: &str, filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        self.with_table(table, |t| t.filter(|ngram| ngram.iter().any(|w| w == word), filter))
+    }
+
+    fn begin(&self, table: &TableName, filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        self.with_table(table, |t| t.filter(|ngram| ngram[0] == BEGIN, filter))
+    }
+
+    fn sources(&self, table: &TableName, word: &str) -> Result<Vec<Provenance>> {
+        self.with_table(table, |t| {
+            let mut sources: Vec<Provenance> = Vec::new();
+            for provenance in t.ngrams.iter().filter(|(ngram, _)| ngram.iter().any(|w| w == word)).flat_map(|(_, p)| p) {
+                match sources.iter_mut().find(|p| p.source == provenance.source) {
+                    Some(known) => {
+                        known.count += provenance.count;
+                        known.first_seen = known.first_seen.min(provenance.first_seen);
+                        known.last_seen = known.last_seen.max(provenance.last_seen);
+                    }
+                    None => sources.push(provenance.clone()),
+                }
+            }
+            sources
+        })
+    }
+
+    fn forget(&self, user: &str) -> Result<Forgotten> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.subtract(|contributor, _| contributor == user))
+    }
+
+    fn open_batch(&self, table: &TableName, source: &Source) -> Result<Batch> {
+        let mut state = self.state.lock().unwrap();
+        if !state.tables.contains_key(table) {
+            return Err(Error::TableNotFound(String::from(table.as_str())));
+        }
+
+        let now = unix_time();
+        if let Some(batch) = state.batches.iter().rev().find(|batch| batch.is_open_for(table, source, now)) {
+            return Ok(batch.clone());
+        }
+
+        let batch = Batch {
+            id: state.batches.len() as i64 + 1,
+            table: table.clone(),
+            source: source.clone(),
+            created_at: now,
+            undone: false,
+        };
+        state.batches.push(batch.clone());
+        Ok(batch)
+    }
+
+    fn batches(&self, table: &TableName, limit: usize) -> Result<Vec<Batch>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.batches.iter().rev().filter(|batch| batch.table == *table).take(limit).cloned().collect())
+    }
+
+    fn undo(&self, table: &TableName, id: i64) -> Result<Option<Forgotten>> {
+        let mut state = self.state.lock().unwrap();
+        match state.batches.iter_mut().find(|batch| batch.id == id && batch.table == *table && !batch.undone) {
+            Some(batch) => batch.undone = true,
+            None => return Ok(None),
+        }
+        Ok(Some(state.subtract(|_, batch| batch == id)))
+    }
+
+    fn get_all_users(&self) -> Result<HashMap<String, UserAccount>> {
+        Ok(self.state.lock().unwrap().users.clone())
+    }
+
+    fn insert_user(&self, user: &UserAccount) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.users.entry(user.user_id.clone()).or_insert_with(|| user.clone());
+        Ok(())
+    }
+
+    fn update_user(&self, user: &UserAccount) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(existing) = state.users.get_mut(&user.user_id) {
+            *existing = UserAccount { is_admin: existing.is_admin, ..user.clone() };
+        }
+        Ok(())
+    }
+
+    fn set_admin(&self, user_id: &str, is_admin: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(existing) = state.users.get_mut(user_id) {
+            existing.is_admin = is_admin;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(ngram: &[&str]) -> Vec<String> {
+        ngram.iter().map(|word| String::from(*word)).collect()
+    }
+
+    // a store with one table of the default order
+    fn store() -> (MemoryStore, TableName) {
+        let store = MemoryStore::new();
+        let table = TableName::new("scratch_test").unwrap();
+        store.create_lexeme_table(&table, DEFAULT_ORDER).unwrap();
+        (store, table)
+    }
+
+    #[test]
+    fn only_created_tables_are_there() {
+        let store = MemoryStore::new();
+        assert!(store.fetch_lexems_tables_list().unwrap().is_empty());
+        let batch = store.open_batch(&TableName::default(), &Source::new("1", SourceKind::Chat));
+        assert!(matches!(batch, Err(Error::TableNotFound(_))));
+    }
+
+    #[test]
+    fn inserted_ngrams_are_found() {
+        let (store, table) = store();
+        let batch = store.open_batch(&table, &Source::new("1", SourceKind::Chat)).unwrap();
+        store.insert(&batch, "a b c", "1").unwrap();
+        store.insert(&batch, "a b d", "1").unwrap();
+        let any = SourceFilter::any();
+
+        let mut right = store.right(&table, &words(&["a", "b"]), &any).unwrap();
+        right.sort();
+        assert_eq!(right, vec![(words(&["a", "b", "c"]), 1), (words(&["a", "b", "d"]), 1)]);
+        assert_eq!(store.left(&table, &words(&["a", "b"]), &any).unwrap(), vec![(words(&[BEGIN, "a", "b"]), 2)]);
+        assert_eq!(store.lexeme(&table, "c", &any).unwrap().len(), 2);
+        assert_eq!(store.count(&table, "a", &any).unwrap(), 4);
+        assert_eq!(store.begin(&table, &any).unwrap(), vec![(words(&[BEGIN, "a", "b"]), 2)]);
+
+        let documents = SourceFilter { chat: None, kind: Some(SourceKind::Document) };
+        assert!(store.right(&table, &words(&["a", "b"]), &documents).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forget_takes_only_what_the_user_taught() {
+        let (store, table) = store();
+        let batch = store.open_batch(&table, &Source::new("1", SourceKind::Chat)).unwrap();
+        store.insert(&batch, "a b c", "1").unwrap();
+        store.insert(&batch, "a b d", "2").unwrap();
+
+        let forgotten = store.forget("1").unwrap();
+        assert_eq!((forgotten.ngrams, forgotten.count, forgotten.removed), (3, 3, 2));
+        assert_eq!(store.right(&table, &words(&["a", "b"]), &SourceFilter::any()).unwrap(), vec![(words(&["a", "b", "d"]), 1)]);
+        assert_eq!(store.count(&table, "a", &SourceFilter::any()).unwrap(), 2);
+        assert_eq!(store.forget("1").unwrap().ngrams, 0);
+    }
+
+    #[test]
+    fn undo_takes_back_a_batch_of_its_table() {
+        let (store, table) = store();
+        let other = TableName::new("scratch_other").unwrap();
+        store.create_lexeme_table(&other, DEFAULT_ORDER).unwrap();
+        let chat = store.open_batch(&table, &Source::new("1", SourceKind::Chat)).unwrap();
+        store.insert(&chat, "a b c", "1").unwrap();
+        let document = store.open_batch(&table, &Source::new("1", SourceKind::Document)).unwrap();
+        assert_ne!(chat.id, document.id);
+        store.insert_many(&document, &["a b c", "c d"], "1").unwrap();
+
+        assert!(store.undo(&other, document.id).unwrap().is_none());
+        let forgotten = store.undo(&table, document.id).unwrap().unwrap();
+        assert_eq!((forgotten.count, forgotten.removed), (5, 5));
+        assert!(store.undo(&table, document.id).unwrap().is_none());
+        assert_eq!(store.count(&table, "a", &SourceFilter::any()).unwrap(), 2);
+        assert!(store.lexeme(&table, "d", &SourceFilter::any()).unwrap().is_empty());
+        assert!(store.batches(&table, 10).unwrap()[0].undone);
+    }
+}
@@ -0,0 +1,576 @@
+use crate::sqlite::QueriesForTable;
+use crate::store::TableName;
+use log::{debug, info, warn};
+use r2d2_sqlite::rusqlite::params;
+use r2d2_sqlite::rusqlite::{Connection, Error, OptionalExtension};
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Schema history of the database. Every migration runs in its own transaction
+// together with the bump of `schema_version`, so a failed one leaves the
+// database exactly at the previous version. Never edit an applied migration,
+// append a new one instead.
+
+const CREATE_VERSION_DB: &str = "CREATE TABLE IF NOT EXISTS schema_version (\
+                            `version` INTEGER PRIMARY KEY, \
+                            `description` TEXT NOT NULL, \
+                            `applied_at` INTEGER NOT NULL);";
+
+// the schema as it was before migrations appeared, databases of that time
+// already have all of it and get only version 1 recorded
+const CREATE_LIST_DB: &str = "CREATE TABLE IF NOT EXISTS lexems_list (\
+                            `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+                            `lexeme_table` TEXT, \
+                            UNIQUE (`lexeme_table`));";
+const CREATE_DB: &str = "CREATE TABLE IF NOT EXISTS lexems (\
+                            `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+                            `lexeme1` TEXT, \
+                            `lexeme2` TEXT, \
+                            `lexeme3` TEXT, \
+                            `count` INT NOT NULL DEFAULT '0', \
+                            UNIQUE (`lexeme1`, `lexeme2`, `lexeme3`));";
+const INSERT_DEFAULT_TABLE: &str = "INSERT OR IGNORE INTO lexems_list (`lexeme_table`) VALUES ('lexems');";
+const CREATE_USER_DB: &str = "CREATE TABLE IF NOT EXISTS user_profiles (\
+                            `id` INTEGER PRIMARY KEY AUTOINCREMENT,\
+                            `user_id` TEXT,\
+                            `is_admin` INT NOT NULL DEFAULT '0',\
+                            `answer_mode` INT NOT NULL DEFAULT '1',\
+                            `lexeme_table` TEXT NOT NULL DEFAULT 'lexems', \
+                            UNIQUE (`user_id`));";
+
+const CREATE_WORDS_DB: &str = "CREATE TABLE IF NOT EXISTS words (\
+                            `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+                            `word` TEXT NOT NULL, \
+                            UNIQUE (`word`));";
+
+type MigrationFn = fn(&Connection) -> Result<(), Error>;
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    apply: MigrationFn,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "initial schema", apply: initial_schema },
+    Migration { version: 2, description: "per-chat temperature", apply: add_user_temperature },
+    Migration { version: 3, description: "order of lexeme tables", apply: add_table_order },
+    Migration { version: 4, description: "shared words dictionary", apply: words_dictionary },
+    Migration { version: 5, description: "indexes for generation queries", apply: generation_indexes },
+    Migration { version: 6, description: "per-chat learning mode", apply: add_user_learn_mode },
+    Migration { version: 7, description: "per-chat reply rules", apply: add_user_reply_rules },
+    Migration { version: 8, description: "seeded replies", apply: add_user_seeded_replies },
+    Migration { version: 9, description: "length limits", apply: add_user_length_limits },
+    Migration { version: 10, description: "copied words limit", apply: add_user_max_copied },
+    Migration { version: 11, description: "provenance of n-grams", apply: ngram_provenance },
+    Migration { version: 12, description: "per-chat sources", apply: add_user_source },
+    Migration { version: 13, description: "contributions of users", apply: contributions },
+    Migration { version: 14, description: "ingestion batches", apply: ingestion_batches },
+    Migration { version: 15, description: "lowercase table names", apply: lowercase_table_names },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(Error),
+    DatabaseIsNewer { database: i64, binary: i64 },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(e) => write!(f, "migration failed: {}", e),
+            MigrationError::DatabaseIsNewer { database, binary } => write!(
+                f,
+                "database schema version {} is newer than {} supported by this binary",
+                database, binary
+            ),
+        }
+    }
+}
+
+impl From<Error> for MigrationError {
+    fn from(e: Error) -> MigrationError {
+        MigrationError::Sqlite(e)
+    }
+}
+
+pub fn current_version(conn: &Connection) -> Result<i64, Error> {
+    conn.execute(CREATE_VERSION_DB, params![])?;
+    let version: Option<i64> = conn
+        .query_row("SELECT MAX(`version`) FROM schema_version;", params![], |row| row.get(0))
+        .optional()?
+        .flatten();
+    Ok(version.unwrap_or(0))
+}
+
+// Brings the database up to the latest version, refuses to touch a database
+// written by a newer binary
+pub fn run(conn: &mut Connection) -> Result<(), MigrationError> {
+    apply(conn, MIGRATIONS)
+}
+
+fn apply(conn: &mut Connection, migrations: &[Migration]) -> Result<(), MigrationError> {
+    let current = current_version(conn)?;
+    let latest = migrations.last().map_or(0, |m| m.version);
+
+    if current > latest {
+        return Err(MigrationError::DatabaseIsNewer { database: current, binary: latest });
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        info!("Applying migration {}: {}", migration.version, migration.description);
+
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (`version`, `description`, `applied_at`) VALUES (?1, ?2, ?3);",
+            params![migration.version, migration.description, unix_time()],
+        )?;
+        tx.commit()?;
+    }
+
+    debug!("Database schema is at version {}", latest);
+    Ok(())
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+pub fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool, Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info(`{}`);", table))?;
+    let columns = stmt
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>, Error>>()?;
+    Ok(columns.iter().any(|c| c == column))
+}
+
+fn initial_schema(conn: &Connection) -> Result<(), Error> {
+    conn.execute(CREATE_LIST_DB, params![])?;
+    conn.execute(CREATE_DB, params![])?;
+    conn.execute(INSERT_DEFAULT_TABLE, params![])?;
+    conn.execute(CREATE_USER_DB, params![])?;
+    Ok(())
+}
+
+fn add_user_temperature(conn: &Connection) -> Result<(), Error> {
+    if !column_exists(conn, "user_profiles", "temperature")? {
+        conn.execute("ALTER TABLE user_profiles ADD COLUMN `temperature` REAL NOT NULL DEFAULT '1.0';", params![])?;
+    }
+    Ok(())
+}
+
+// chats have been learning from every message before
+fn add_user_learn_mode(conn: &Connection) -> Result<(), Error> {
+    if !column_exists(conn, "user_profiles", "learn_mode")? {
+        conn.execute("ALTER TABLE user_profiles ADD COLUMN `learn_mode` INT NOT NULL DEFAULT '1';", params![])?;
+    }
+    Ok(())
+}
+
+// chats have been answered on every message before
+fn add_user_reply_rules(conn: &Connection) -> Result<(), Error> {
+    let columns = [
+        ("reply_probability", "REAL NOT NULL DEFAULT '1.0'"),
+        ("mention_only", "INT NOT NULL DEFAULT '0'"),
+        ("triggers", "TEXT NOT NULL DEFAULT ''"),
+        ("cooldown", "INT NOT NULL DEFAULT '0'"),
+    ];
+    for (column, definition) in columns.iter() {
+        if !column_exists(conn, "user_profiles", column)? {
+            conn.execute(&format!("ALTER TABLE user_profiles ADD COLUMN `{}` {};", column, definition), params![])?;
+        }
+    }
+    Ok(())
+}
+
+fn add_user_seeded_replies(conn: &Connection) -> Result<(), Error> {
+    if !column_exists(conn, "user_profiles", "seeded_replies")? {
+        conn.execute("ALTER TABLE user_profiles ADD COLUMN `seeded_replies` INT NOT NULL DEFAULT '1';", params![])?;
+    }
+    Ok(())
+}
+
+// zero is no limit
+fn add_user_length_limits(conn: &Connection) -> Result<(), Error> {
+    for column in &["min_words", "max_words", "min_chars", "max_chars"] {
+        if !column_exists(conn, "user_profiles", column)? {
+            conn.execute(&format!("ALTER TABLE user_profiles ADD COLUMN `{}` INT NOT NULL DEFAULT '0';", column), params![])?;
+        }
+    }
+    Ok(())
+}
+
+fn add_user_max_copied(conn: &Connection) -> Result<(), Error> {
+    if !column_exists(conn, "user_profiles", "max_copied")? {
+        conn.execute("ALTER TABLE user_profiles ADD COLUMN `max_copied` INT NOT NULL DEFAULT '8';", params![])?;
+    }
+    Ok(())
+}
+
+// tables listed before configurable orders appeared are all of order 2
+fn add_table_order(conn: &Connection) -> Result<(), Error> {
+    if !column_exists(conn, "lexems_list", "ngram_order")? {
+        conn.execute("ALTER TABLE lexems_list ADD COLUMN `ngram_order` INT NOT NULL DEFAULT '2';", params![])?;
+    }
+    Ok(())
+}
+
+// Every lexeme table which is both listed and really exists, with its order.
+// SQLite finds tables whatever the case of their names, so `Foo` listed before
+// names were lowercased is the table `foo`, and only its first listing counts.
+fn lexeme_tables(conn: &Connection) -> Result<Vec<(TableName, usize)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT l.`lexeme_table`, l.`ngram_order` FROM lexems_list l \
+        JOIN sqlite_master m ON m.`type` = 'table' AND m.`name` = l.`lexeme_table` COLLATE NOCASE \
+        ORDER BY l.`id`;",
+    )?;
+    let tables = stmt
+        .query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?
+        .collect::<Result<Vec<(String, i64)>, Error>>()?;
+
+    let mut seen = HashSet::new();
+    Ok(tables
+        .into_iter()
+        .filter_map(|(name, order)| match TableName::new(&name) {
+            Some(table) => Some((table, order as usize)),
+            None => {
+                warn!("Skipping lexeme table with a bad name {:?}", name);
+                None
+            }
+        })
+        .filter(|(table, _)| seen.insert(table.clone()))
+        .collect())
+}
+
+// Lexeme tables kept a full TEXT copy of every word in each row, now they
+// keep ids from the shared dictionary. Row ids and counts are preserved,
+// a word which was NULL stays NULL.
+fn words_dictionary(conn: &Connection) -> Result<(), Error> {
+    conn.execute(CREATE_WORDS_DB, params![])?;
+
+    for (table, order) in lexeme_tables(conn)? {
+        info!("Moving words of table {} into the dictionary", table);
+        let columns = (1..=order + 1).map(|i| format!("`lexeme{}`", i)).collect::<Vec<String>>();
+        let rebuilt = format!("{}__words", table);
+
+        for column in &columns {
+            conn.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO words (`word`) SELECT DISTINCT {} FROM `{}` WHERE {} IS NOT NULL;",
+                    column, table, column
+                ),
+                params![],
+            )?;
+        }
+
+        conn.execute(
+            &format!(
+                "CREATE TABLE `{}` (\
+                `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+                {}, \
+                `count` INT NOT NULL DEFAULT '0', \
+                UNIQUE ({}));",
+                rebuilt,
+                columns.iter().map(|c| format!("{} INTEGER", c)).collect::<Vec<String>>().join(", "),
+                columns.join(", ")
+            ),
+            params![],
+        )?;
+
+        let ids = (1..=order + 1).map(|i| format!("w{}.`id`", i)).collect::<Vec<String>>();
+        let joins = (1..=order + 1)
+            .map(|i| format!("LEFT JOIN words w{} ON w{}.`word` = t.`lexeme{}`", i, i, i))
+            .collect::<Vec<String>>();
+        conn.execute(
+            &format!(
+                "INSERT INTO `{}` (`id`, {}, `count`) SELECT t.`id`, {}, t.`count` FROM `{}` t {};",
+                rebuilt,
+                columns.join(", "),
+                ids.join(", "),
+                table,
+                joins.join(" ")
+            ),
+            params![],
+        )?;
+
+        conn.execute(&format!("DROP TABLE `{}`;", table), params![])?;
+        conn.execute(&format!("ALTER TABLE `{}` RENAME TO `{}`;", rebuilt, table), params![])?;
+    }
+    Ok(())
+}
+
+// n-grams learned before are nobody's, they can't be forgotten
+fn contributions(conn: &Connection) -> Result<(), Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS contributions (\
+        `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+        `user_id` TEXT NOT NULL, \
+        `lexeme_table` TEXT NOT NULL, \
+        `ngram` INTEGER NOT NULL, \
+        `count` INT NOT NULL DEFAULT '0', \
+        UNIQUE (`user_id`, `lexeme_table`, `ngram`));",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Contributions made so far belong to batch 0, which can't be undone. The
+// unique constraint changes, so the table is rebuilt.
+fn ingestion_batches(conn: &Connection) -> Result<(), Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS batches (\
+        `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+        `lexeme_table` TEXT NOT NULL, \
+        `source_chat` TEXT NOT NULL, \
+        `source_kind` TEXT NOT NULL, \
+        `created_at` INT NOT NULL, \
+        `undone` INT NOT NULL DEFAULT '0');",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS `batches__source` ON batches (`lexeme_table`, `source_chat`, `source_kind`);",
+        params![],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE contributions__batches (\
+        `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+        `user_id` TEXT NOT NULL, \
+        `lexeme_table` TEXT NOT NULL, \
+        `ngram` INTEGER NOT NULL, \
+        `batch` INTEGER NOT NULL DEFAULT '0', \
+        `count` INT NOT NULL DEFAULT '0', \
+        UNIQUE (`user_id`, `lexeme_table`, `ngram`, `batch`));",
+        params![],
+    )?;
+    conn.execute(
+        "INSERT INTO contributions__batches (`id`, `user_id`, `lexeme_table`, `ngram`, `count`) \
+        SELECT `id`, `user_id`, `lexeme_table`, `ngram`, `count` FROM contributions;",
+        params![],
+    )?;
+    conn.execute("DROP TABLE contributions;", params![])?;
+    conn.execute("ALTER TABLE contributions__batches RENAME TO contributions;", params![])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS `contributions__batch` ON contributions (`batch`);", params![])?;
+    Ok(())
+}
+
+// empty ones match any source
+fn add_user_source(conn: &Connection) -> Result<(), Error> {
+    for column in &["source_chat", "source_kind"] {
+        if !column_exists(conn, "user_profiles", column)? {
+            conn.execute(&format!("ALTER TABLE user_profiles ADD COLUMN `{}` TEXT NOT NULL DEFAULT '';", column), params![])?;
+        }
+    }
+    Ok(())
+}
+
+// Every n-gram is counted per source from now on, whatever was learned before
+// belongs to an unknown source. The unique constraint changes, so the tables
+// are rebuilt like for the dictionary.
+fn ngram_provenance(conn: &Connection) -> Result<(), Error> {
+    for (table, order) in lexeme_tables(conn)? {
+        info!("Adding provenance to table {}", table);
+        let columns = (1..=order + 1).map(|i| format!("`lexeme{}`", i)).collect::<Vec<String>>();
+        let rebuilt = format!("{}__provenance", table);
+
+        conn.execute(
+            &format!(
+                "CREATE TABLE `{}` (\
+                `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+                {}, \
+                `source_chat` TEXT NOT NULL DEFAULT '', \
+                `source_kind` TEXT NOT NULL DEFAULT 'unknown', \
+                `first_seen` INT NOT NULL DEFAULT '0', \
+                `last_seen` INT NOT NULL DEFAULT '0', \
+                `count` INT NOT NULL DEFAULT '0', \
+                UNIQUE ({}, `source_chat`, `source_kind`));",
+                rebuilt,
+                columns.iter().map(|c| format!("{} INTEGER", c)).collect::<Vec<String>>().join(", "),
+                columns.join(", ")
+            ),
+            params![],
+        )?;
+        conn.execute(
+            &format!(
+                "INSERT INTO `{}` (`id`, {}, `count`) SELECT `id`, {}, `count` FROM `{}`;",
+                rebuilt,
+                columns.join(", "),
+                columns.join(", "),
+                table
+            ),
+            params![],
+        )?;
+
+        conn.execute(&format!("DROP TABLE `{}`;", table), params![])?;
+        conn.execute(&format!("ALTER TABLE `{}` RENAME TO `{}`;", rebuilt, table), params![])?;
+        for index in QueriesForTable::indexes(&table, order) {
+            conn.execute(&index, params![])?;
+        }
+    }
+    Ok(())
+}
+
+fn generation_indexes(conn: &Connection) -> Result<(), Error> {
+    for (table, order) in lexeme_tables(conn)? {
+        info!("Creating indexes for table {}", table);
+        for index in QueriesForTable::indexes(&table, order) {
+            conn.execute(&index, params![])?;
+        }
+    }
+    conn.execute("ANALYZE;", params![])?;
+    Ok(())
+}
+
+// SQLite table names ignore case, but names in lexems_list didn't, so `Foo`
+// could be recorded next to `foo` with an order the real table doesn't have.
+// The first recorded name of a table keeps its order, the rest are dropped.
+fn lowercase_table_names(conn: &Connection) -> Result<(), Error> {
+    let dropped = conn.execute(
+        "DELETE FROM lexems_list WHERE EXISTS (SELECT 1 FROM lexems_list l \
+        WHERE lower(l.`lexeme_table`) = lower(lexems_list.`lexeme_table`) AND l.`id` < lexems_list.`id`);",
+        params![],
+    )?;
+    if dropped > 0 {
+        warn!("Dropped {} lexeme tables differing only in case", dropped);
+    }
+
+    conn.execute("UPDATE lexems_list SET `lexeme_table` = lower(`lexeme_table`);", params![])?;
+    conn.execute("UPDATE user_profiles SET `lexeme_table` = lower(`lexeme_table`);", params![])?;
+    conn.execute("UPDATE batches SET `lexeme_table` = lower(`lexeme_table`);", params![])?;
+    conn.execute("UPDATE OR IGNORE contributions SET `lexeme_table` = lower(`lexeme_table`);", params![])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::SqliteConn;
+    use crate::store::{LexemeStore, SourceFilter};
+    use r2d2::{Pool, PooledConnection};
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    // a lexeme table as `/changetable` created it before migrations appeared
+    const CREATE_BASELINE_TABLE: &str = "CREATE TABLE `{}` (\
+                            `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
+                            `lexeme1` TEXT, \
+                            `lexeme2` TEXT, \
+                            `lexeme3` TEXT, \
+                            `count` INT NOT NULL DEFAULT '0', \
+                            UNIQUE (`lexeme1`, `lexeme2`, `lexeme3`));";
+
+    // a database in memory as it was before migrations, a pool of one keeps it alive
+    fn baseline() -> PooledConnection<SqliteConnectionManager> {
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        let conn = pool.get().unwrap();
+        initial_schema(&conn).unwrap();
+        conn
+    }
+
+    fn baseline_table(conn: &Connection, name: &str, rows: &[(i64, Option<&str>, Option<&str>, Option<&str>, i64)]) {
+        conn.execute(&CREATE_BASELINE_TABLE.replace("{}", name), params![]).unwrap();
+        conn.execute("INSERT OR IGNORE INTO lexems_list (`lexeme_table`) VALUES (?1);", params![name]).unwrap();
+        for (id, lexeme1, lexeme2, lexeme3, count) in rows {
+            conn.execute(
+                &format!("INSERT INTO `{}` (`id`, `lexeme1`, `lexeme2`, `lexeme3`, `count`) VALUES (?1, ?2, ?3, ?4, ?5);", name),
+                params![id, lexeme1, lexeme2, lexeme3, count],
+            )
+            .unwrap();
+        }
+    }
+
+    fn words(ngram: &[&str]) -> Vec<String> {
+        ngram.iter().map(|word| String::from(*word)).collect()
+    }
+
+    #[test]
+    fn mixed_case_tables_are_migrated() {
+        let mut conn = baseline();
+        baseline_table(&conn, "Foo", &[
+            (1, Some("#beg#"), Some("hello"), Some("world"), 3),
+            (2, Some("hello"), Some("world"), Some("#end#"), 3),
+        ]);
+        run(&mut conn).unwrap();
+
+        let store = SqliteConn::new(conn).unwrap();
+        let table = TableName::new("Foo").unwrap();
+        assert_eq!(store.count(&table, "world", &SourceFilter::any()).unwrap(), 6);
+        assert_eq!(
+            store.right(&table, &words(&["#beg#", "hello"]), &SourceFilter::any()).unwrap(),
+            vec![(words(&["#beg#", "hello", "world"]), 3)]
+        );
+        assert!(store.fetch_lexems_tables_list().unwrap().contains(&String::from("foo")));
+    }
+
+    #[test]
+    fn words_move_into_the_dictionary() {
+        let mut conn = baseline();
+        conn.execute("DELETE FROM lexems_list WHERE `lexeme_table` = 'lexems';", params![]).unwrap();
+        conn.execute("DROP TABLE lexems;", params![]).unwrap();
+        baseline_table(&conn, "lexems", &[
+            (7, Some("#beg#"), Some("it's"), Some("a"), 2),
+            (8, Some("it's"), Some("a"), Some("'quote'"), 5),
+            (12, Some("a"), Some("'quote'"), Some("#end#"), 1),
+            (15, Some("a"), Some("'quote'"), None, 4),
+        ]);
+        run(&mut conn).unwrap();
+
+        let ids = {
+            let mut stmt = conn.prepare("SELECT `id`, `count`, `lexeme3` IS NULL FROM lexems ORDER BY `id`;").unwrap();
+            let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+            rows.collect::<Result<Vec<(i64, i64, bool)>, Error>>().unwrap()
+        };
+        assert_eq!(ids, vec![(7, 2, false), (8, 5, false), (12, 1, false), (15, 4, true)]);
+
+        let store = SqliteConn::new(conn).unwrap();
+        let table = TableName::default();
+        assert_eq!(
+            store.right(&table, &words(&["it's", "a"]), &SourceFilter::any()).unwrap(),
+            vec![(words(&["it's", "a", "'quote'"]), 5)]
+        );
+        assert_eq!(
+            store.right(&table, &words(&["a", "'quote'"]), &SourceFilter::any()).unwrap(),
+            vec![(words(&["a", "'quote'", "#end#"]), 1)]
+        );
+        assert_eq!(store.count(&table, "'quote'", &SourceFilter::any()).unwrap(), 10);
+        assert_eq!(store.count(&table, "it's", &SourceFilter::any()).unwrap(), 7);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let latest = MIGRATIONS.last().unwrap().version;
+        let mut conn = baseline();
+        current_version(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (`version`, `description`, `applied_at`) VALUES (?1, 'future', 0);",
+            params![latest + 1],
+        )
+        .unwrap();
+
+        match run(&mut conn) {
+            Err(MigrationError::DatabaseIsNewer { database, binary }) => assert_eq!((database, binary), (latest + 1, latest)),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failed_migrations_are_rolled_back() {
+        fn half_done(conn: &Connection) -> Result<(), Error> {
+            conn.execute("ALTER TABLE user_profiles ADD COLUMN `half_done` INT;", params![])?;
+            conn.execute("SELECT * FROM no_such_table;", params![])?;
+            Ok(())
+        }
+        let migrations = [
+            Migration { version: 1, description: "initial schema", apply: initial_schema },
+            Migration { version: 2, description: "half done", apply: half_done },
+        ];
+        let mut conn = baseline();
+
+        assert!(apply(&mut conn, &migrations).is_err());
+        assert_eq!(current_version(&conn).unwrap(), 1);
+        assert!(!column_exists(&conn, "user_profiles", "half_done").unwrap());
+    }
+}
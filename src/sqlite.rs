@@ -1,122 +1,305 @@
+use crate::error::{Error, Result};
+use crate::generator::Limits;
+use crate::migrations;
+use crate::store::*;
 use crate::user::*;
-use log::{debug, info, trace};
+use log::{info, trace, warn};
 use r2d2::{Pool, PooledConnection};
-use r2d2_sqlite::rusqlite::params;
-use r2d2_sqlite::rusqlite::ToSql;
-use r2d2_sqlite::rusqlite::*;
+use r2d2_sqlite::rusqlite;
+use r2d2_sqlite::rusqlite::{params, CachedStatement, OptionalExtension, ToSql};
+use r2d2_sqlite::rusqlite::types::ToSqlOutput;
 use r2d2_sqlite::SqliteConnectionManager;
 use std::collections::HashMap;
-
-pub const DEFAULT_TABLE: &str = "lexems";
-const CREATE_DB: &str = "CREATE TABLE IF NOT EXISTS lexems (\
-                            `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
-                            `lexeme1` TEXT, \
-                            `lexeme2` TEXT, \
-                            `lexeme3` TEXT, \
-                            `count` INT NOT NULL DEFAULT '0', \
-                            UNIQUE (`lexeme1`, `lexeme2`, `lexeme3`));";
-const CREATE_USER_DB: &str = "CREATE TABLE IF NOT EXISTS user_profiles (\
-                            `id` INTEGER PRIMARY KEY AUTOINCREMENT,\
-                            `user_id` TEXT,\
-                            `is_admin` INT NOT NULL DEFAULT '0',\
-                            `answer_mode` INT NOT NULL DEFAULT '1',\
-                            `lexeme_table` TEXT NOT NULL DEFAULT 'lexems', \
-                            UNIQUE (`user_id`));";
-const CREATE_LIST_DB: &str = "CREATE TABLE IF NOT EXISTS lexems_list (\
-                            `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
-                            `lexeme_table` TEXT,
-                            UNIQUE (`lexeme_table`));";
-const INSERT_DEFAULT_TABLE: &str = "INSERT OR IGNORE INTO lexems_list (`lexeme_table`) VALUES ('lexems');";
-// begin and end markers for text
-const BEGIN: &str = "#beg#";
-const END: &str = "#end#";
-
-// maximum length of recursion limit in select_left and select_right functions
-const MAXIMUM_RECURSION_DEPTH: i32 = 500;
+use std::time::Duration;
 
 pub struct QueriesForTable;
 
+// All the queries below are built for a table of some order `n`, which means
+// `n` words of context and `n + 1` lexeme columns in each row. Lexeme columns
+// keep ids from the `words` dictionary, queries take and return plain words.
+// Every n-gram has a row for each source it was learned from, candidates sum
+// the counts over sources the two parameters after the words let through.
 impl QueriesForTable {
-    pub fn create(table_name: &str) -> String {
+    fn columns(order: usize) -> Vec<String> {
+        (1..=order + 1).map(|i| format!("`lexeme{}`", i)).collect()
+    }
+
+    // `lexemeA = ?1 AND lexemeB = ?2 ...` for a run of columns starting from `first`, by ids
+    fn id_condition(first: usize, length: usize) -> String {
+        (0..length)
+            .map(|i| format!("`lexeme{}` = ?{}", first + i, i + 1))
+            .collect::<Vec<String>>()
+            .join(" AND ")
+    }
+
+    // same as above, but by words
+    fn word_condition(first: usize, length: usize) -> String {
+        (0..length)
+            .map(|i| format!("t.`lexeme{}` = {}", first + i, QueriesForTable::word_id(i + 1)))
+            .collect::<Vec<String>>()
+            .join(" AND ")
+    }
+
+    fn any_condition(order: usize) -> String {
+        (1..=order + 1)
+            .map(|i| format!("t.`lexeme{}` = {}", i, QueriesForTable::word_id(1)))
+            .collect::<Vec<String>>()
+            .join(" OR ")
+    }
+
+    fn word_id(param: usize) -> String {
+        format!("(SELECT `id` FROM words WHERE `word` = ?{})", param)
+    }
+
+    // a NULL parameter matches any source
+    fn source_condition(first: usize) -> String {
+        format!(
+            "(?{} IS NULL OR t.`source_chat` = ?{}) AND (?{} IS NULL OR t.`source_kind` = ?{})",
+            first,
+            first,
+            first + 1,
+            first + 1
+        )
+    }
+
+    // selects words of the n-gram and its count, `t` is the lexeme table
+    fn select_words(table: &TableName, order: usize) -> String {
+        let words = (1..=order + 1)
+            .map(|i| format!("w{}.`word`", i))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let joins = (1..=order + 1)
+            .map(|i| format!("JOIN words w{} ON w{}.`id` = t.`lexeme{}`", i, i, i))
+            .collect::<Vec<String>>()
+            .join(" ");
+        format!("SELECT {}, SUM(t.`count`) FROM `{}` t {}", words, table, joins)
+    }
+
+    // n-grams matching the condition on words, which takes `params` parameters
+    fn candidates(table: &TableName, order: usize, condition: String, params: usize) -> String {
+        format!(
+            "{} WHERE ({}) AND {} GROUP BY {};",
+            QueriesForTable::select_words(table, order),
+            condition,
+            QueriesForTable::source_condition(params + 1),
+            QueriesForTable::columns(order).iter().map(|c| format!("t.{}", c)).collect::<Vec<String>>().join(", ")
+        )
+    }
+
+    pub fn create(table: &TableName, order: usize) -> String {
+        let columns = QueriesForTable::columns(order);
         format!(
-            "CREATE TABLE IF NOT EXISTS {} (\
+            "CREATE TABLE IF NOT EXISTS `{}` (\
             `id` INTEGER PRIMARY KEY AUTOINCREMENT, \
-            `lexeme1` TEXT, \
-            `lexeme2` TEXT, \
-            `lexeme3` TEXT, \
+            {}, \
+            `source_chat` TEXT NOT NULL DEFAULT '', \
+            `source_kind` TEXT NOT NULL DEFAULT 'unknown', \
+            `first_seen` INT NOT NULL DEFAULT '0', \
+            `last_seen` INT NOT NULL DEFAULT '0', \
             `count` INT NOT NULL DEFAULT '0', \
-            UNIQUE (`lexeme1`, `lexeme2`, `lexeme3`));",
-            table_name
+            UNIQUE ({}, `source_chat`, `source_kind`));",
+            table,
+            columns.iter().map(|c| format!("{} INTEGER", c)).collect::<Vec<String>>().join(", "),
+            columns.join(", ")
         )
     }
-    
-    pub fn insert_table(table_name: &str) -> String {
-        format!("INSERT OR IGNORE INTO lexems_list (`lexeme_table`) VALUES ('{}');", table_name)
+
+    // The unique constraint already gives an index on (lexeme1, ..., lexemeN+1),
+    // which serves `right` and lookups by lexeme1. `left` gets its own index,
+    // which also serves lookups by lexeme2, and the rest of columns get single
+    // ones, so `exists` and `lexeme` can use an index for every branch of OR
+    pub fn indexes(table: &TableName, order: usize) -> Vec<String> {
+        let mut indexes = vec![format!(
+            "CREATE INDEX IF NOT EXISTS `{}__left` ON `{}` ({});",
+            table,
+            table,
+            QueriesForTable::columns(order)[1..].join(", ")
+        )];
+
+        for i in 3..=order + 1 {
+            indexes.push(format!(
+                "CREATE INDEX IF NOT EXISTS `{}__lexeme{}` ON `{}` (`lexeme{}`);",
+                table, i, table, i
+            ));
+        }
+        indexes
+    }
+
+    pub fn insert_table() -> &'static str {
+        "INSERT OR IGNORE INTO lexems_list (`lexeme_table`, `ngram_order`) VALUES (?1, ?2);"
+    }
+
+    pub fn table_order() -> &'static str {
+        "SELECT `ngram_order` FROM lexems_list WHERE `lexeme_table` = ?1;"
+    }
+
+    pub fn insert_word() -> &'static str {
+        "INSERT OR IGNORE INTO words (`word`) VALUES (?1);"
+    }
+
+    pub fn word_by_text() -> &'static str {
+        "SELECT `id` FROM words WHERE `word` = ?1;"
     }
 
-    pub fn exists(table_name: &str) -> String {
+    // both take ids of the n-gram, then the source chat and kind and the time
+    pub fn insert(table: &TableName, order: usize) -> String {
         format!(
-            "SELECT count FROM {} \
-            WHERE lexeme1 = ?1 OR lexeme2 = ?1 OR lexeme3 = ?1;",
-            table_name
+            "INSERT OR IGNORE INTO `{}` ({}, `source_chat`, `source_kind`, `first_seen`, `last_seen`) VALUES ({}, ?{}, ?{}, ?{}, ?{});",
+            table,
+            QueriesForTable::columns(order).join(", "),
+            (1..=order + 1).map(|i| format!("?{}", i)).collect::<Vec<String>>().join(", "),
+            order + 2,
+            order + 3,
+            order + 4,
+            order + 4
         )
     }
 
-    pub fn left(table_name: &str) -> String {
+    pub fn increment(table: &TableName, order: usize) -> String {
+        format!(
+            "UPDATE `{}` SET count = count + 1, `last_seen` = ?{} WHERE {} AND `source_chat` = ?{} AND `source_kind` = ?{};",
+            table,
+            order + 4,
+            QueriesForTable::id_condition(1, order + 1),
+            order + 2,
+            order + 3
+        )
+    }
+
+    // the row `insert` and `increment` got to, takes their parameters but the time
+    pub fn ngram_id(table: &TableName, order: usize) -> String {
+        format!(
+            "SELECT `id` FROM `{}` WHERE {} AND `source_chat` = ?{} AND `source_kind` = ?{};",
+            table,
+            QueriesForTable::id_condition(1, order + 1),
+            order + 2,
+            order + 3
+        )
+    }
+
+    // Contributions count how many times every user taught a row of some table
+    // in every batch. Both queries take the user, the table, the row and the batch.
+    pub fn insert_contribution() -> &'static str {
+        "INSERT OR IGNORE INTO contributions (`user_id`, `lexeme_table`, `ngram`, `batch`) VALUES (?1, ?2, ?3, ?4);"
+    }
+
+    pub fn increment_contribution() -> &'static str {
+        "UPDATE contributions SET `count` = `count` + 1 \
+        WHERE `user_id` = ?1 AND `lexeme_table` = ?2 AND `ngram` = ?3 AND `batch` = ?4;"
+    }
+
+    // The queries below choose contributions by `column`, which is either
+    // `user_id` or `batch`, and take its value and then the table if any
+    pub fn contributed_tables(column: &str) -> String {
+        format!("SELECT DISTINCT `lexeme_table` FROM contributions WHERE `{}` = ?1;", column)
+    }
+
+    pub fn delete_contributions(column: &str) -> String {
+        format!("DELETE FROM contributions WHERE `{}` = ?1;", column)
+    }
+
+    // rows of the table the contributions are about and how many times they were taught
+    pub fn contributed(table: &TableName, column: &str) -> String {
         format!(
-            "SELECT lexeme1, lexeme2, lexeme3 FROM {} \
-            WHERE lexeme2 = ?1 AND lexeme3 = ?2 ORDER BY RANDOM() DESC LIMIT 0,1;",
-            table_name
+            "SELECT COUNT(DISTINCT c.`ngram`), COALESCE(SUM(c.`count`), 0) FROM contributions c JOIN `{}` t ON t.`id` = c.`ngram` \
+            WHERE c.`{}` = ?1 AND c.`lexeme_table` = ?2;",
+            table, column
         )
     }
 
-    pub fn right(table_name: &str) -> String {
+    pub fn subtract(table: &TableName, column: &str) -> String {
         format!(
-            "SELECT lexeme1, lexeme2, lexeme3 FROM {} \
-            WHERE lexeme1 = ?1 AND lexeme2 = ?2 ORDER BY RANDOM() DESC LIMIT 0,1;",
-            table_name
+            "UPDATE `{}` SET `count` = `count` - (SELECT SUM(c.`count`) FROM contributions c \
+            WHERE c.`{}` = ?1 AND c.`lexeme_table` = ?2 AND c.`ngram` = `{}`.`id`) \
+            WHERE `id` IN (SELECT `ngram` FROM contributions WHERE `{}` = ?1 AND `lexeme_table` = ?2);",
+            table, column, table, column
         )
     }
 
-    pub fn lexeme(table_name: &str) -> String {
+    // rows nobody taught anymore
+    pub fn remove_forgotten(table: &TableName) -> String {
+        format!("DELETE FROM `{}` WHERE `count` <= 0;", table)
+    }
+
+    pub fn insert_batch() -> &'static str {
+        "INSERT INTO batches (`lexeme_table`, `source_chat`, `source_kind`, `created_at`) VALUES (?1, ?2, ?3, ?4);"
+    }
+
+    // all the batch queries return rows for `row_to_batch`
+    pub fn latest_batch() -> &'static str {
+        "SELECT `id`, `lexeme_table`, `source_chat`, `source_kind`, `created_at`, `undone` FROM batches \
+        WHERE `lexeme_table` = ?1 AND `source_chat` = ?2 AND `source_kind` = ?3 ORDER BY `id` DESC LIMIT 1;"
+    }
+
+    pub fn batches() -> &'static str {
+        "SELECT `id`, `lexeme_table`, `source_chat`, `source_kind`, `created_at`, `undone` FROM batches \
+        WHERE `lexeme_table` = ?1 ORDER BY `id` DESC LIMIT ?2;"
+    }
+
+    pub fn batch() -> &'static str {
+        "SELECT `id`, `lexeme_table`, `source_chat`, `source_kind`, `created_at`, `undone` FROM batches WHERE `id` = ?1;"
+    }
+
+    pub fn undo_batch() -> &'static str {
+        "UPDATE batches SET `undone` = 1 WHERE `id` = ?1;"
+    }
+
+    pub fn exists(table: &TableName, order: usize) -> String {
         format!(
-            "SELECT lexeme1, lexeme2, lexeme3 FROM {} \
-            WHERE lexeme1 = ?1 OR lexeme2 = ?1 OR lexeme3 = ?1 \
-            ORDER BY RANDOM() LIMIT 0,1;",
-            table_name
+            "SELECT t.count FROM `{}` t WHERE ({}) AND {};",
+            table,
+            QueriesForTable::any_condition(order),
+            QueriesForTable::source_condition(2)
         )
     }
 
-    pub fn begin(table_name: &str) -> String {
+    pub fn left(table: &TableName, order: usize) -> String {
+        QueriesForTable::candidates(table, order, QueriesForTable::word_condition(2, order), order)
+    }
+
+    pub fn right(table: &TableName, order: usize) -> String {
+        QueriesForTable::candidates(table, order, QueriesForTable::word_condition(1, order), order)
+    }
+
+    pub fn lexeme(table: &TableName, order: usize) -> String {
+        QueriesForTable::candidates(table, order, QueriesForTable::any_condition(order), 1)
+    }
+
+    pub fn begin(table: &TableName, order: usize) -> String {
+        QueriesForTable::candidates(table, order, QueriesForTable::word_condition(1, 1), 1)
+    }
+
+    pub fn sources(table: &TableName, order: usize) -> String {
         format!(
-            "SELECT lexeme1, lexeme2, lexeme3 FROM {} \
-            WHERE lexeme1 = '#beg#' ORDER BY RANDOM() LIMIT 0,1;",
-            table_name
+            "SELECT t.`source_chat`, t.`source_kind`, SUM(t.`count`), MIN(t.`first_seen`), MAX(t.`last_seen`) \
+            FROM `{}` t WHERE {} GROUP BY t.`source_chat`, t.`source_kind` ORDER BY SUM(t.`count`) DESC;",
+            table,
+            QueriesForTable::any_condition(order)
         )
     }
 }
 
+// how long a query waits for a lock before it fails with DatabaseBusy
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct SqliteDB {
     pool: Pool<SqliteConnectionManager>
 }
 
 impl SqliteDB {
-    pub fn new(path: &str) -> SqliteDB {
+    pub fn new(path: &str) -> Result<SqliteDB> {
         info!("SqliteDB starting");
         let manager = SqliteConnectionManager::file(path);
-        let pool = r2d2::Pool::new(manager).unwrap();
+        let pool = r2d2::Pool::new(manager)?;
 
-        let conn = pool.get().unwrap();
-        conn.execute(CREATE_LIST_DB, params![]).unwrap();
-        conn.execute(CREATE_DB, params![]).unwrap();
-        conn.execute(INSERT_DEFAULT_TABLE, params![]).unwrap();
-        conn.execute(CREATE_USER_DB, params![]).unwrap();
+        let mut conn = pool.get()?;
+        migrations::run(&mut conn)?;
 
-        SqliteDB { pool }
+        Ok(SqliteDB { pool })
     }
 
-    pub fn get_conn(&self) -> SqliteConn {
-        SqliteConn::new(self.pool.get().unwrap())
+    pub fn get_conn(&self) -> Result<SqliteConn> {
+        SqliteConn::new(self.pool.get()?)
     }
 }
 
@@ -124,344 +307,541 @@ pub struct SqliteConn {
     conn: PooledConnection<SqliteConnectionManager>
 }
 
-fn query_statement<P>(stmt: &mut CachedStatement<'_>, params: P) -> Vec<String>
+impl ToSql for SourceKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+// the words of a query followed by the source filter
+fn with_filter<'p, W: ToSql>(words: &'p [W], filter: &'p SourceFilter) -> Vec<&'p dyn ToSql> {
+    let mut params = words.iter().map(|word| word as &dyn ToSql).collect::<Vec<&dyn ToSql>>();
+    params.push(&filter.chat);
+    params.push(&filter.kind);
+    params
+}
+
+// batches of tables with a broken name can't be there, they are skipped
+fn row_to_batch(row: &rusqlite::Row<'_>) -> rusqlite::Result<Option<Batch>> {
+    let id: i64 = row.get(0)?;
+    let table: String = row.get(1)?;
+    let chat: String = row.get(2)?;
+    let kind: String = row.get(3)?;
+    let created_at: i64 = row.get(4)?;
+    let undone: bool = row.get(5)?;
+
+    Ok(TableName::new(&table).map(|table| Batch {
+        id,
+        table,
+        source: Source::new(&chat, SourceKind::parse(&kind).unwrap_or(SourceKind::Unknown)),
+        created_at,
+        undone,
+    }))
+}
+
+// rows are `order + 1` lexems followed by their count
+fn query_candidates<P>(stmt: &mut CachedStatement<'_>, params: P, order: usize) -> Result<Vec<(Vec<String>, i64)>>
 where
     P: IntoIterator,
     P::Item: ToSql,
 {
-    match stmt.query_row(params, |row| {
-        let lexeme1: String = row.get_unwrap(0);
-        let lexeme2: String = row.get_unwrap(1);
-        let lexeme3: String = row.get_unwrap(2);
-        Ok(vec![lexeme1, lexeme2, lexeme3])
-    }) {
-        Ok(v) => v,
-        Err(e) => {
-            info!("Error happened in sql query: {:?}", e);
-            return vec![
-                String::from(BEGIN),
-                String::from("Not found"),
-                String::from(END),
-            ];
-        }
-    }
+    let candidates = stmt
+        .query_map(params, |row| {
+            let lexems = (0..=order)
+                .map(|i| row.get::<_, String>(i))
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            let count: i64 = row.get(order + 1)?;
+            Ok((lexems, count))
+        })?
+        .collect::<rusqlite::Result<Vec<(Vec<String>, i64)>>>()?;
+    Ok(candidates)
 }
 
 impl SqliteConn {
-    pub fn new(conn: PooledConnection<SqliteConnectionManager>) -> SqliteConn {
-        conn.busy_handler(Some(|_| {
-            std::thread::sleep(std::time::Duration::from_millis(1));
-            true
-        }))
-        .unwrap();
+    pub fn new(conn: PooledConnection<SqliteConnectionManager>) -> Result<SqliteConn> {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
 
-        SqliteConn { conn }
+        Ok(SqliteConn { conn })
     }
 
-    // Here we have always a new table name, so just create and push it into hashmap
-    pub fn create_lexeme_table(&mut self, name: &str) {
-        self.conn
-            .execute(&QueriesForTable::create(name), params![])
-            .unwrap();
-            
-        self.conn
-            .execute(&QueriesForTable::insert_table(name), params![])
-            .unwrap();
-        info!("Created a new table '{}' and new queries for it", name);
-    }
-
-    pub fn is_exist(&self, table: &str, word: String) -> Option<i32> {
-        if !word.is_empty() {
-            let mut stmt = self
-                .conn
-                .prepare_cached(&QueriesForTable::exists(table))
-                .unwrap();
-            let count = stmt
-                .query_and_then(params![&word], |row| {
-                    let cnt: i32 = row.get_unwrap(0);
-                    Ok(cnt)
-                })
-                .unwrap()
-                .fold(0, |a, b: std::result::Result<i32, Error>| a + b.unwrap());
-
-            Some(count)
-        } else {
-            None
+    // Runs `f` between BEGIN and COMMIT, anything failing inside rolls the
+    // transaction back, so the pooled connection is never left in the middle of one
+    fn in_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        self.conn.execute("BEGIN DEFERRED TRANSACTION", params![])?;
+
+        let result = f().and_then(|value| {
+            self.conn.execute("COMMIT", params![])?;
+            Ok(value)
+        });
+
+        if result.is_err() {
+            if let Err(e) = self.conn.execute("ROLLBACK", params![]) {
+                warn!("Rollback failed: {}", e);
+            }
         }
+        result
     }
 
-    pub fn insert(&self, table: &str, text: String) -> () {
-        let mut splitted: Vec<&str> = text.trim().split_whitespace().collect();
+    // Takes contributions chosen by `column` out of their tables, rows nobody
+    // taught anymore are removed. Expects to be in a transaction.
+    fn subtract(&self, column: &str, value: &dyn ToSql) -> Result<Forgotten> {
+        let tables = self
+            .conn
+            .prepare_cached(&QueriesForTable::contributed_tables(column))?
+            .query_map(&[value], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        let mut forgotten = Forgotten::default();
+        for table in tables.iter().filter_map(|t| TableName::new(t)) {
+            match self.table_order(&table) {
+                Ok(_) => (),
+                Err(Error::TableNotFound(_)) => {
+                    warn!("Contributions to a missing table {}", table);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
 
-        splitted.insert(0, BEGIN);
-        splitted.push(END);
+            let (ngrams, count) = self.conn.query_row(
+                &QueriesForTable::contributed(&table, column),
+                params![value, table.as_str()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )?;
+            self.conn.execute(&QueriesForTable::subtract(&table, column), params![value, table.as_str()])?;
+            let removed = self.conn.execute(&QueriesForTable::remove_forgotten(&table), params![])? as i64;
 
-        for s in &splitted {
-            trace!("{}", s);
+            info!("Subtracted {} n-grams by {} from {}, {} removed", ngrams, column, table, removed);
+            forgotten.add(Forgotten { ngrams, count, removed });
         }
 
-        self.conn
-            .execute("BEGIN DEFERRED TRANSACTION", params![])
-            .unwrap();
-        for x in 0..(splitted.len() - 2) {
-            let insert_sql = format!(
-                "INSERT OR IGNORE INTO {} (\
-                                       `lexeme1`, \
-                                       `lexeme2`, \
-                                       `lexeme3`) \
-                 VALUES ('{}', '{}', '{}');",
-                table,
-                splitted[x],
-                splitted[x + 1],
-                splitted[x + 2],
-            );
-
-            let update_sql = format!(
-                "UPDATE {} \
-                SET count = count+1 \
-                WHERE \
-                lexeme1 = '{}' AND lexeme2 = '{}' AND lexeme3 = '{}';",
-                table,
-                splitted[x],
-                splitted[x + 1],
-                splitted[x + 2]
-            );
-
-            trace!("Inserting {}", insert_sql);
-            match self.conn.execute(&insert_sql, params![]) {
-                Err(e) => trace!("Insert was unsuccessful: {}", e),
-                _ => trace!("Insert is successful"),
-            }
-
-            trace!("Inserting {}", update_sql);
-            match self.conn.execute(&update_sql, params![]) {
-                Err(e) => trace!("Insert was unsuccessful: {}", e),
-                _ => trace!("Insert is successful"),
-            }
-        }
-        self.conn.execute("COMMIT", params![]).unwrap();
+        self.conn.execute(&QueriesForTable::delete_contributions(column), &[value])?;
+        Ok(forgotten)
     }
 
-    pub fn select(&self, table: &str, input: String) -> String {
-        let word = input.trim();
-
-        if word.is_empty() {
-            let result = self.select_random(table);
-            debug!("Found by random: {}", result);
-            result
-        } else {
-            let result = self.select_lexeme(table, word);
-            debug!("Found by word {}: {}", word, result);
-            result
-        }
+    // id of the word in the dictionary, the word is added if it's new
+    fn word_id(&self, word: &str) -> Result<i64> {
+        self.conn
+            .prepare_cached(QueriesForTable::insert_word())?
+            .execute(params![word])?;
+        let id = self.conn
+            .prepare_cached(QueriesForTable::word_by_text())?
+            .query_row(params![word], |row| row.get(0))?;
+        Ok(id)
     }
+}
 
-    fn select_lexeme(&self, table: &str, word: &str) -> String {
-        let mut stmt = self
-            .conn
-            .prepare_cached(&QueriesForTable::lexeme(table))
-            .unwrap();
-        let init = query_statement(&mut stmt, params![word]);
+impl LexemeStore for SqliteConn {
+    // Here we have always a new table name, so just create and push it into hashmap
+    fn create_lexeme_table(&self, name: &TableName, order: usize) -> Result<usize> {
+        self.conn.execute(&QueriesForTable::create(name, order), params![])?;
+        self.conn.execute(QueriesForTable::insert_table(), params![name.as_str(), order as i64])?;
+        info!("Created a new table '{}' and new queries for it", name);
 
-        if BEGIN.eq(&init[0]) && END.eq(&init[2]) {
-            return String::from(&init[1]);
+        let order = self.table_order(name)?;
+        for index in QueriesForTable::indexes(name, order) {
+            self.conn.execute(&index, params![])?;
         }
+        Ok(order)
+    }
 
-        if BEGIN.eq(&init[0]) {
-            return self.select_right(table, &init[1], &init[2]);
+    fn reindex(&self, table: &TableName) -> Result<()> {
+        let order = self.table_order(table)?;
+        for index in QueriesForTable::indexes(table, order) {
+            self.conn.execute(&index, params![])?;
         }
+        self.conn.execute(&format!("REINDEX `{}`;", table), params![])?;
+        self.conn.execute(&format!("ANALYZE `{}`;", table), params![])?;
+        info!("Reindexed table '{}'", table);
+        Ok(())
+    }
 
-        if END.eq(&init[2]) {
-            return self.select_left(table, &init[0], &init[1], false);
+    fn table_order(&self, table: &TableName) -> Result<usize> {
+        let order = self
+            .conn
+            .prepare_cached(QueriesForTable::table_order())?
+            .query_row(params![table.as_str()], |row| row.get::<_, i64>(0))
+            .optional()?;
+        match order {
+            Some(order) => Ok(order as usize),
+            None => Err(Error::TableNotFound(String::from(table.as_str()))),
         }
+    }
 
-        let mut result_string = self.select_left(table, &init[0], &init[1], true);
-        result_string.push_str(" ");
-        result_string.push_str(&self.select_right(table, &init[1], &init[2]));
-        result_string
+    fn insert_ngrams(&self, batch: &Batch, ngrams: &[Vec<String>], user: &str) -> Result<()> {
+        let (table, source) = (&batch.table, &batch.source);
+        let order = self.table_order(table)?;
+        let now = unix_time();
+
+        self.in_transaction(|| {
+            let mut insert_stmt = self.conn.prepare_cached(&QueriesForTable::insert(table, order))?;
+            let mut increment_stmt = self.conn.prepare_cached(&QueriesForTable::increment(table, order))?;
+            let mut ngram_id_stmt = self.conn.prepare_cached(&QueriesForTable::ngram_id(table, order))?;
+            let mut insert_contribution_stmt = self.conn.prepare_cached(QueriesForTable::insert_contribution())?;
+            let mut increment_contribution_stmt = self.conn.prepare_cached(QueriesForTable::increment_contribution())?;
+
+            let mut word_ids: HashMap<&str, i64> = HashMap::new();
+
+            for ngram in ngrams.iter().filter(|ngram| ngram.len() == order + 1) {
+                trace!("Inserting {:?} into {}", ngram, table);
+                let mut ids = Vec::with_capacity(ngram.len());
+                for word in ngram {
+                    let id = match word_ids.get(word.as_str()) {
+                        Some(id) => *id,
+                        None => {
+                            let id = self.word_id(word)?;
+                            word_ids.insert(word, id);
+                            id
+                        }
+                    };
+                    ids.push(id);
+                }
+
+                let mut values = ids.iter().map(|id| id as &dyn ToSql).collect::<Vec<&dyn ToSql>>();
+                values.extend_from_slice(&[&source.chat, &source.kind, &now]);
+
+                insert_stmt.execute(&values)?;
+                increment_stmt.execute(&values)?;
+
+                let ngram_id: i64 = ngram_id_stmt.query_row(&values[..order + 3], |row| row.get(0))?;
+                insert_contribution_stmt.execute(params![user, table.as_str(), ngram_id, batch.id])?;
+                increment_contribution_stmt.execute(params![user, table.as_str(), ngram_id, batch.id])?;
+            }
+            Ok(())
+        })
     }
 
-    fn select_random(&self, table: &str) -> String {
-        let mut stmt = self
-            .conn
-            .prepare_cached(&QueriesForTable::begin(table))
-            .unwrap();
-        let init = query_statement(&mut stmt, params![]);
+    fn count(&self, table: &TableName, word: &str, filter: &SourceFilter) -> Result<i64> {
+        let order = self.table_order(table)?;
+        let mut stmt = self.conn.prepare_cached(&QueriesForTable::exists(table, order))?;
+        let counts = stmt
+            .query_map(with_filter(&[word], filter), |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        Ok(counts.iter().sum())
+    }
 
-        if END.eq(&init[2]) {
-            return String::from(&init[1]);
-        }
+    fn right(&self, table: &TableName, context: &[String], filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        let order = self.table_order(table)?;
+        let mut stmt = self.conn.prepare_cached(&QueriesForTable::right(table, order))?;
+        query_candidates(&mut stmt, with_filter(context, filter), order)
+    }
 
-        // #beg# is always first
-        self.select_right(table, &init[1], &init[2])
+    fn left(&self, table: &TableName, context: &[String], filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        let order = self.table_order(table)?;
+        let mut stmt = self.conn.prepare_cached(&QueriesForTable::left(table, order))?;
+        query_candidates(&mut stmt, with_filter(context, filter), order)
     }
 
-    // maybe I can fold select_left and select_right into one universal function
-    // just need to reinvent direction argument...
-    fn select_left(&self, table: &str, lexeme2: &str, lexeme3: &str, remove_last: bool) -> String {
-        let mut stmt = self
-            .conn
-            .prepare_cached(&QueriesForTable::left(table))
-            .unwrap();
-        let mut result = vec![String::from(lexeme3), String::from(lexeme2)];
-        let mut result_string = String::new();
-
-        trace!("{} {}", &lexeme2, &lexeme3);
-
-        let get_first_element = |v: &Vec<String>| v[v.len() - 1].clone();
-        let get_after_first_element = |v: &Vec<String>| v[v.len() - 2].clone();
-
-        let mut select = |word2, word3| {
-            let ret = query_statement(&mut stmt, params![&word2, &word3]);
-            if BEGIN.eq(&ret[0]) {
-                None
-            } else {
-                Some(ret)
-            }
-        };
+    fn lexeme(&self, table: &TableName, word: &str, filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        let order = self.table_order(table)?;
+        let mut stmt = self.conn.prepare_cached(&QueriesForTable::lexeme(table, order))?;
+        query_candidates(&mut stmt, with_filter(&[word], filter), order)
+    }
 
-        let mut recursion = 0;
-        while let Some(lexems) =
-            select(get_first_element(&result), get_after_first_element(&result))
-        {
-            result.push(lexems[0].clone());
-            recursion = recursion + 1;
-            if recursion >= MAXIMUM_RECURSION_DEPTH {
-                break;
-            }
-        }
+    fn begin(&self, table: &TableName, filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>> {
+        let order = self.table_order(table)?;
+        let mut stmt = self.conn.prepare_cached(&QueriesForTable::begin(table, order))?;
+        query_candidates(&mut stmt, with_filter(&[BEGIN], filter), order)
+    }
 
-        if remove_last {
-            result.remove(0);
-        }
+    fn sources(&self, table: &TableName, word: &str) -> Result<Vec<Provenance>> {
+        let order = self.table_order(table)?;
+        let mut stmt = self.conn.prepare_cached(&QueriesForTable::sources(table, order))?;
+        let sources = stmt
+            .query_map(params![word], |row| {
+                let kind: String = row.get(1)?;
+                Ok(Provenance {
+                    source: Source::new(&row.get::<_, String>(0)?, SourceKind::parse(&kind).unwrap_or(SourceKind::Unknown)),
+                    count: row.get(2)?,
+                    first_seen: row.get(3)?,
+                    last_seen: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Provenance>>>()?;
+        Ok(sources)
+    }
 
-        let reverse_string = |s: &str| s.chars().rev().collect::<String>();
+    fn forget(&self, user: &str) -> Result<Forgotten> {
+        self.in_transaction(|| self.subtract("user_id", &user))
+    }
 
-        for (i, s) in result.iter().enumerate() {
-            trace!("{}: {}", i, s);
-            result_string.push_str(&reverse_string(&s));
-            result_string.push_str(" ");
+    fn open_batch(&self, table: &TableName, source: &Source) -> Result<Batch> {
+        // no batches for tables which aren't there
+        self.table_order(table)?;
+        let now = unix_time();
+        let latest = self
+            .conn
+            .prepare_cached(QueriesForTable::latest_batch())?
+            .query_row(params![table.as_str(), &source.chat, source.kind], row_to_batch)
+            .optional()?
+            .flatten();
+        if let Some(batch) = latest.filter(|batch| batch.is_open_for(table, source, now)) {
+            return Ok(batch);
         }
 
-        reverse_string(&result_string)
+        self.conn
+            .prepare_cached(QueriesForTable::insert_batch())?
+            .execute(params![table.as_str(), &source.chat, source.kind, now])?;
+        let batch = Batch {
+            id: self.conn.last_insert_rowid(),
+            table: table.clone(),
+            source: source.clone(),
+            created_at: now,
+            undone: false,
+        };
+        info!("Opened batch {} for {:?} in {}", batch.id, source, table);
+        Ok(batch)
     }
 
-    fn select_right(&self, table: &str, lexeme1: &str, lexeme2: &str) -> String {
-        let mut stmt = self
+    fn batches(&self, table: &TableName, limit: usize) -> Result<Vec<Batch>> {
+        let batches = self
             .conn
-            .prepare_cached(&QueriesForTable::right(table))
-            .unwrap();
-        let mut result = vec![String::from(lexeme1), String::from(lexeme2)];
-        let mut result_string = String::new();
-
-        trace!("{} {}", &lexeme1, &lexeme2);
-
-        let get_last_element = |v: &Vec<String>| v[v.len() - 1].clone();
-        let get_prev_last_element = |v: &Vec<String>| v[v.len() - 2].clone();
-
-        let mut select = |word1, word2| {
-            let ret = query_statement(&mut stmt, params![&word1, &word2]);
-            if END.eq(&ret[2]) {
-                None
-            } else {
-                Some(ret)
-            }
-        };
+            .prepare_cached(QueriesForTable::batches())?
+            .query_map(params![table.as_str(), limit as i64], row_to_batch)?
+            .collect::<rusqlite::Result<Vec<Option<Batch>>>>()?;
+        Ok(batches.into_iter().flatten().collect())
+    }
 
-        let mut recursion = 0;
-        while let Some(lexems) = select(get_prev_last_element(&result), get_last_element(&result)) {
-            result.push(get_last_element(&lexems));
-            recursion = recursion + 1;
-            if recursion >= MAXIMUM_RECURSION_DEPTH {
-                break;
+    fn undo(&self, table: &TableName, id: i64) -> Result<Option<Forgotten>> {
+        self.in_transaction(|| {
+            let batch = self
+                .conn
+                .prepare_cached(QueriesForTable::batch())?
+                .query_row(params![id], row_to_batch)
+                .optional()?
+                .flatten();
+            match batch {
+                Some(ref batch) if batch.table == *table && !batch.undone => (),
+                _ => return Ok(None),
             }
-        }
 
-        for (i, s) in result.iter().enumerate() {
-            trace!("{}: {}", i, s);
-            result_string.push_str(s);
-            result_string.push_str(" ");
-        }
-
-        result_string
+            let forgotten = self.subtract("batch", &id)?;
+            self.conn.execute(QueriesForTable::undo_batch(), params![id])?;
+            Ok(Some(forgotten))
+        })
     }
 
-    pub fn fetch_lexems_tables_list(&self) -> Vec<String> {
-        let mut stmt = self.conn.prepare_cached("SELECT `lexeme_table` FROM lexems_list;").unwrap();
-        stmt.query_and_then(params![], |row| {
-            let table: String = row.get_unwrap(0);
-            Ok(table)
-        }).unwrap().map(|item: Result<String, Error>| item.unwrap()).collect::<Vec<String>>()
+    fn fetch_lexems_tables_list(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached("SELECT `lexeme_table` FROM lexems_list;")?;
+        let tables = stmt
+            .query_map(params![], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(tables)
     }
-    
+
     // API for user management
     // So I will use only insert user into this DB
-    pub fn insert_user(&self, user: &UserAccount) {
-        self.conn
-            .execute("BEGIN DEFERRED TRANSACTION", params![])
-            .unwrap();
-
-        let query = "INSERT OR IGNORE INTO user_profiles (`user_id`, `is_admin`, `answer_mode`, `lexeme_table`) VALUES (?1, ?2, ?3, ?4)";
-        self.conn
-            .execute(
+    fn insert_user(&self, user: &UserAccount) -> Result<()> {
+        self.in_transaction(|| {
+            let query = "INSERT OR IGNORE INTO user_profiles (`user_id`, `is_admin`, `answer_mode`, `learn_mode`, `lexeme_table`, `temperature`, \
+                        `reply_probability`, `mention_only`, `triggers`, `cooldown`, `seeded_replies`, \
+                        `min_words`, `max_words`, `min_chars`, `max_chars`, `max_copied`, \
+                        `source_chat`, `source_kind`) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)";
+            self.conn.execute(
                 &query,
-                params![&user.user_id, user.is_admin, user.answer_mode, &user.lexeme_table],
-            )
-            .unwrap();
-
-        self.conn.execute("COMMIT", params![]).unwrap();
+                params![
+                    &user.user_id, user.is_admin, user.answer_mode, user.learn_mode, &user.lexeme_table, user.temperature,
+                    user.reply_probability, user.mention_only, user.triggers.join(" "), user.cooldown, user.seeded_replies,
+                    user.limits.min_words as i64, user.limits.max_words as i64, user.limits.min_chars as i64, user.limits.max_chars as i64,
+                    user.max_copied as i64,
+                    user.source.chat.as_deref().unwrap_or_default(), user.source.kind.map_or("", |kind| kind.as_str())
+                ],
+            )?;
+            Ok(())
+        })
     }
 
     // I will use it only on startup
-    pub fn get_all_users(&mut self) -> HashMap<String, UserAccount> {
-        let mut map = HashMap::new();
+    fn get_all_users(&self) -> Result<HashMap<String, UserAccount>> {
         let mut stmt = self
             .conn
-            .prepare_cached("SELECT `user_id`, `is_admin`, `answer_mode`, `lexeme_table` FROM user_profiles")
-            .unwrap();
-        let _n = stmt
-            .query_and_then(params![], |row| {
-                let user_id: String = row.get_unwrap(0);
-                let is_admin: bool = row.get_unwrap(1);
-                let answer_mode: bool = row.get_unwrap(2);
-                let lexeme_table: String = row.get_unwrap(3);
+            .prepare_cached("SELECT `user_id`, `is_admin`, `answer_mode`, `learn_mode`, `lexeme_table`, `temperature`, \
+                            `reply_probability`, `mention_only`, `triggers`, `cooldown`, `seeded_replies`, \
+                            `min_words`, `max_words`, `min_chars`, `max_chars`, `max_copied`, `source_chat`, `source_kind` FROM user_profiles")?;
+        let users = stmt
+            .query_map(params![], |row| {
+                let user_id: String = row.get(0)?;
+                let is_admin: bool = row.get(1)?;
+                let answer_mode: bool = row.get(2)?;
+                let learn_mode: bool = row.get(3)?;
+                let lexeme_table: String = row.get(4)?;
+                let temperature: f64 = row.get(5)?;
+                let reply_probability: f64 = row.get(6)?;
+                let mention_only: bool = row.get(7)?;
+                let triggers: String = row.get(8)?;
+                let cooldown: i64 = row.get(9)?;
+                let seeded_replies: bool = row.get(10)?;
+                let limits = Limits {
+                    min_words: row.get::<_, i64>(11)? as usize,
+                    max_words: row.get::<_, i64>(12)? as usize,
+                    min_chars: row.get::<_, i64>(13)? as usize,
+                    max_chars: row.get::<_, i64>(14)? as usize,
+                };
+                let max_copied = row.get::<_, i64>(15)? as usize;
+                let source_chat: String = row.get(16)?;
+                let source_kind: String = row.get(17)?;
+                let source = SourceFilter {
+                    chat: Some(source_chat).filter(|chat| !chat.is_empty()),
+                    kind: SourceKind::parse(&source_kind),
+                };
 
                 info!(
-                    "fetching profile = {} {} {} {}",
-                    &user_id, is_admin, answer_mode, &lexeme_table
+                    "fetching profile = {} {} {} {} {} {} {} {} {:?} {} {} {:?} {} {}",
+                    &user_id, is_admin, answer_mode, learn_mode, &lexeme_table, temperature,
+                    reply_probability, mention_only, &triggers, cooldown, seeded_replies, limits, max_copied, source
                 );
 
                 Ok(UserAccount {
                     user_id,
                     is_admin,
                     answer_mode,
+                    learn_mode,
                     lexeme_table,
+                    temperature,
+                    reply_probability,
+                    mention_only,
+                    triggers: triggers.split_whitespace().map(String::from).collect(),
+                    cooldown,
+                    seeded_replies,
+                    limits,
+                    max_copied,
+                    source,
                 })
-            })
-            .unwrap()
-            .map(|item: Result<UserAccount, Error>| {
-                let user = item.unwrap();
-                map.insert(user.user_id.clone(), user);
-            })
-            .collect::<()>();
-        map
-    }
+            })?
+            .collect::<rusqlite::Result<Vec<UserAccount>>>()?;
 
-    pub fn update_user(&self, user: &UserAccount) {
-        self.conn
-            .execute("BEGIN DEFERRED TRANSACTION", params![])
-            .unwrap();
+        Ok(users.into_iter().map(|user| (user.user_id.clone(), user)).collect())
+    }
 
-        let query =
-            "UPDATE user_profiles SET `is_admin` = ?2, `answer_mode` = ?3, `lexeme_table` = ?4 WHERE user_id = ?1";
-        trace!("Updating user: {}", query);
-        self.conn
-            .execute(
+    fn update_user(&self, user: &UserAccount) -> Result<()> {
+        self.in_transaction(|| {
+            let query =
+                "UPDATE user_profiles SET `answer_mode` = ?2, `learn_mode` = ?3, `lexeme_table` = ?4, `temperature` = ?5, \
+                `reply_probability` = ?6, `mention_only` = ?7, `triggers` = ?8, `cooldown` = ?9, `seeded_replies` = ?10, \
+                `min_words` = ?11, `max_words` = ?12, `min_chars` = ?13, `max_chars` = ?14, `max_copied` = ?15, \
+                `source_chat` = ?16, `source_kind` = ?17 WHERE user_id = ?1";
+            trace!("Updating user: {}", query);
+            self.conn.execute(
                 &query,
-                params![&user.user_id, user.is_admin, user.answer_mode, &user.lexeme_table],
-            )
-            .unwrap();
+                params![
+                    &user.user_id, user.answer_mode, user.learn_mode, &user.lexeme_table, user.temperature,
+                    user.reply_probability, user.mention_only, user.triggers.join(" "), user.cooldown, user.seeded_replies,
+                    user.limits.min_words as i64, user.limits.max_words as i64, user.limits.min_chars as i64, user.limits.max_chars as i64,
+                    user.max_copied as i64,
+                    user.source.chat.as_deref().unwrap_or_default(), user.source.kind.map_or("", |kind| kind.as_str())
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn set_admin(&self, user_id: &str, is_admin: bool) -> Result<()> {
+        self.conn.execute("UPDATE user_profiles SET `is_admin` = ?2 WHERE user_id = ?1", params![user_id, is_admin])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_management::UserManager;
+
+    // a fresh database in memory, a pool of one keeps it alive
+    fn store() -> SqliteConn {
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        let mut conn = pool.get().unwrap();
+        migrations::run(&mut conn).unwrap();
+        SqliteConn::new(conn).unwrap()
+    }
+
+    fn words(ngram: &[&str]) -> Vec<String> {
+        ngram.iter().map(|word| String::from(*word)).collect()
+    }
+
+    #[test]
+    fn sql_in_words_is_just_words() {
+        let store = store();
+        let table = TableName::default();
+        let batch = store.open_batch(&table, &Source::new("1", SourceKind::Chat)).unwrap();
+        store.insert(&batch, r"it's a 'quote' \ DROP TABLE x; --", "1").unwrap();
+
+        let right = store.right(&table, &words(&["a", "'quote'"]), &SourceFilter::any()).unwrap();
+        assert_eq!(right, vec![(words(&["a", "'quote'", r"\"]), 1)]);
+
+        let lexeme = store.lexeme(&table, "x;", &SourceFilter::any()).unwrap();
+        let mut ngrams = lexeme.into_iter().map(|(ngram, _)| ngram).collect::<Vec<Vec<String>>>();
+        ngrams.sort();
+        assert_eq!(
+            ngrams,
+            vec![words(&["DROP", "TABLE", "x;"]), words(&["TABLE", "x;", "--"]), words(&["x;", "--", END])]
+        );
+
+        assert_eq!(store.count(&table, "it's", &SourceFilter::any()).unwrap(), 2);
+        assert_eq!(store.count(&table, "--", &SourceFilter::any()).unwrap(), 2);
+        assert_eq!(store.count(&table, "DROP TABLE x", &SourceFilter::any()).unwrap(), 0);
+        assert_eq!(store.fetch_lexems_tables_list().unwrap(), vec![String::from(DEFAULT_TABLE)]);
+    }
+
+    #[test]
+    fn batches_are_undone_only_in_their_table() {
+        let store = store();
+        let (lexems, other) = (TableName::default(), TableName::new("other").unwrap());
+        store.create_lexeme_table(&other, DEFAULT_ORDER).unwrap();
+        let source = Source::new("1", SourceKind::Document);
+        let batch = store.open_batch(&other, &source).unwrap();
+        store.insert(&batch, "one two three", "1").unwrap();
+
+        assert!(store.undo(&lexems, batch.id).unwrap().is_none());
+        assert_eq!(store.count(&other, "two", &SourceFilter::any()).unwrap(), 3);
+        assert_eq!(store.undo(&other, batch.id).unwrap().unwrap().removed, 3);
+        assert!(store.undo(&other, batch.id).unwrap().is_none());
+        assert_eq!(store.count(&other, "two", &SourceFilter::any()).unwrap(), 0);
+    }
+
+    #[test]
+    fn many_texts_go_in_by_chunks() {
+        let store = store();
+        let table = TableName::default();
+        let batch = store.open_batch(&table, &Source::new("1", SourceKind::Document)).unwrap();
+        let texts = vec!["a b c d"; 4_001];
+        assert_eq!(store.insert_many(&batch, &texts, "1").unwrap(), (4_001, 16_004));
+        assert_eq!(store.right(&table, &words(&["b", "c"]), &SourceFilter::any()).unwrap(), vec![(words(&["b", "c", "d"]), 4_001)]);
+        assert_eq!(store.undo(&table, batch.id).unwrap().unwrap().count, 16_004);
+    }
+
+    #[test]
+    fn settings_never_change_admin_rights() {
+        let store = store();
+        let user = UserAccount { is_admin: false, cooldown: 5, ..UserManager::new(&store).unwrap().get_user(&store, "1").unwrap() };
+        store.set_admin("1", true).unwrap();
+        store.update_user(&user).unwrap();
+
+        let saved = &store.get_all_users().unwrap()["1"];
+        assert!(saved.is_admin);
+        assert_eq!(saved.cooldown, 5);
+    }
+
+    #[test]
+    fn table_names_are_checked() {
+        assert!(TableName::new("a;b").is_none());
+        assert!(TableName::new("a`b").is_none());
+        assert!(TableName::new("sqlite_x").is_none());
+        assert!(TableName::new("words").is_none());
+        assert!(TableName::new("foo__left").is_none());
+        assert!(TableName::new("foo_left").is_some());
+        assert!(TableName::new(&"a".repeat(65)).is_none());
+        assert!(TableName::new(&"a".repeat(64)).is_some());
+        assert_eq!(TableName::new(" quotes_2 ").unwrap().as_str(), "quotes_2");
+    }
 
-        self.conn.execute("COMMIT", params![]).unwrap();
+    #[test]
+    fn table_names_ignore_case() {
+        let store = store();
+        let table = TableName::new("Foo").unwrap();
+        assert_eq!(store.create_lexeme_table(&table, 2).unwrap(), 2);
+        assert_eq!(store.create_lexeme_table(&TableName::new("FOO").unwrap(), 3).unwrap(), 2);
+        assert_eq!(store.table_order(&TableName::new("foo").unwrap()).unwrap(), 2);
     }
 }
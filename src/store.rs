@@ -0,0 +1,312 @@
+use crate::error::Result;
+use crate::user::*;
+use log::{debug, trace};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_TABLE: &str = "lexems";
+
+// begin and end markers for text
+pub const BEGIN: &str = "#beg#";
+pub const END: &str = "#end#";
+
+// order of a table is the number of words a next word depends on
+pub const DEFAULT_ORDER: usize = 2;
+pub const MINIMUM_ORDER: usize = 2;
+pub const MAXIMUM_ORDER: usize = 5;
+
+// tables with this prefix live only in memory and are gone after restart
+pub const SCRATCH_PREFIX: &str = "scratch_";
+
+// longest table name accepted from a chat, sqlite itself doesn't care but we do
+const MAXIMUM_TABLE_NAME_LENGTH: usize = 64;
+
+// service tables which must never be used as lexeme tables
+const RESERVED_TABLE_NAMES: [&str; 6] = ["lexems_list", "user_profiles", "schema_version", "words", "contributions", "batches"];
+
+// messages of a chat go into the same batch for this long
+pub const CHAT_BATCH_SECONDS: i64 = 60 * 60;
+
+// n-grams of many texts go to the store in transactions of about this many,
+// so a big document never has all of its n-grams in memory at once
+const INSERTED_NGRAMS_CHUNK: usize = 10_000;
+
+// Name of a lexeme table which is safe to be spliced into SQL: table names
+// can't be bound as parameters, so everything coming from a chat goes through here.
+// SQLite doesn't tell `Foo` from `foo`, so names are kept lowercase. Indexes
+// and tables being rebuilt are named `table__something` and share the namespace
+// with tables, so `__` is never a part of a table name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TableName(String);
+
+impl TableName {
+    pub fn new(name: &str) -> Option<TableName> {
+        let name = name.trim().to_lowercase();
+
+        let starts_with_letter = name.chars().next().map_or(false, |c| c.is_ascii_alphabetic());
+        let only_word_chars = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let reserved = RESERVED_TABLE_NAMES.contains(&name.as_str()) || name.starts_with("sqlite_") || name.contains("__");
+
+        if starts_with_letter && only_word_chars && !reserved && name.len() <= MAXIMUM_TABLE_NAME_LENGTH {
+            Some(TableName(name))
+        } else {
+            debug!("Rejected table name {:?}", name);
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_scratch(&self) -> bool {
+        self.0.starts_with(SCRATCH_PREFIX)
+    }
+}
+
+impl Default for TableName {
+    fn default() -> TableName {
+        TableName(String::from(DEFAULT_TABLE))
+    }
+}
+
+impl fmt::Display for TableName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// what kind of text n-grams were learned from, n-grams learned before
+// anybody kept track of it are of unknown kind and have no chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SourceKind {
+    Chat,
+    Document,
+    Unknown,
+}
+
+impl SourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceKind::Chat => "chat",
+            SourceKind::Document => "document",
+            SourceKind::Unknown => "unknown",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<SourceKind> {
+        match kind {
+            "chat" => Some(SourceKind::Chat),
+            "document" => Some(SourceKind::Document),
+            "unknown" => Some(SourceKind::Unknown),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Every n-gram is counted separately for each source it was learned from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Source {
+    pub chat: String,
+    pub kind: SourceKind,
+}
+
+impl Source {
+    pub fn new(chat: &str, kind: SourceKind) -> Source {
+        Source { chat: String::from(chat), kind }
+    }
+}
+
+// Restricts candidates to some sources, None matches anything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceFilter {
+    pub chat: Option<String>,
+    pub kind: Option<SourceKind>,
+}
+
+impl SourceFilter {
+    pub fn any() -> SourceFilter {
+        SourceFilter::default()
+    }
+
+    pub fn matches(&self, source: &Source) -> bool {
+        self.chat.as_ref().map_or(true, |chat| *chat == source.chat)
+            && self.kind.map_or(true, |kind| kind == source.kind)
+    }
+}
+
+impl fmt::Display for SourceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.chat, self.kind) {
+            (None, None) => write!(f, "any"),
+            (None, Some(kind)) => write!(f, "{}", kind),
+            (Some(chat), None) => write!(f, "chat {}", chat),
+            (Some(chat), Some(kind)) => write!(f, "{} of chat {}", kind, chat),
+        }
+    }
+}
+
+// how much a source gave to n-grams with some word and when
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    pub source: Source,
+    pub count: i64,
+    // unix time, zero for n-grams of unknown kind
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+// Everything learned at once: a document or messages of a chat over a while,
+// it can be undone as a whole
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub id: i64,
+    pub table: TableName,
+    pub source: Source,
+    // unix time
+    pub created_at: i64,
+    pub undone: bool,
+}
+
+impl Batch {
+    // whether messages of the source coming now still go into this batch
+    pub fn is_open_for(&self, table: &TableName, source: &Source, now: i64) -> bool {
+        source.kind == SourceKind::Chat
+            && !self.undone
+            && self.table == *table
+            && self.source == *source
+            && now - self.created_at < CHAT_BATCH_SECONDS
+    }
+}
+
+// what forgetting a user or undoing a batch took away from the tables
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Forgotten {
+    // n-grams the user had a part in
+    pub ngrams: i64,
+    // how many times they were learned from the user
+    pub count: i64,
+    // n-grams nobody else taught, they are gone completely
+    pub removed: i64,
+}
+
+impl Forgotten {
+    pub fn add(&mut self, other: Forgotten) {
+        self.ngrams += other.ngrams;
+        self.count += other.count;
+        self.removed += other.removed;
+    }
+}
+
+pub fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+// Splits a message into overlapping n-grams of `order + 1` words, bracketed
+// by begin and end markers. Too short messages give nothing.
+pub fn split_ngrams(text: &str, order: usize) -> Vec<Vec<String>> {
+    let mut splitted: Vec<&str> = text.trim().split_whitespace().collect();
+
+    splitted.insert(0, BEGIN);
+    splitted.push(END);
+
+    for s in &splitted {
+        trace!("{}", s);
+    }
+
+    splitted
+        .windows(order + 1)
+        .map(|ngram| ngram.iter().map(|s| String::from(*s)).collect())
+        .collect()
+}
+
+// Everything the bot keeps: lexeme tables and user profiles. Candidates are
+// returned as n-grams of `order + 1` words paired with their counts summed
+// over the sources the filter lets through, picking one of them is up to
+// the generator.
+pub trait LexemeStore {
+    // An already existing table keeps its order, the actual one is returned
+    fn create_lexeme_table(&self, table: &TableName, order: usize) -> Result<usize>;
+    // fails with TableNotFound for a table nobody has created
+    fn table_order(&self, table: &TableName) -> Result<usize>;
+    fn fetch_lexems_tables_list(&self) -> Result<Vec<String>>;
+    // makes sure all indexes are there and rebuilds them
+    fn reindex(&self, table: &TableName) -> Result<()>;
+
+    // adds one to the count of every n-gram in the table and from the source
+    // of the batch, remembering the user who taught it
+    fn insert_ngrams(&self, batch: &Batch, ngrams: &[Vec<String>], user: &str) -> Result<()>;
+    // sum of counts of all n-grams containing the word
+    fn count(&self, table: &TableName, word: &str, filter: &SourceFilter) -> Result<i64>;
+    // n-grams starting with the context
+    fn right(&self, table: &TableName, context: &[String], filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>>;
+    // n-grams ending with the context
+    fn left(&self, table: &TableName, context: &[String], filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>>;
+    // n-grams containing the word anywhere
+    fn lexeme(&self, table: &TableName, word: &str, filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>>;
+    // n-grams starting a message
+    fn begin(&self, table: &TableName, filter: &SourceFilter) -> Result<Vec<(Vec<String>, i64)>>;
+    // every source of n-grams containing the word
+    fn sources(&self, table: &TableName, word: &str) -> Result<Vec<Provenance>>;
+    // takes everything the user taught out of all tables
+    fn forget(&self, user: &str) -> Result<Forgotten>;
+
+    // a new batch for every document, messages of a chat share an open one
+    fn open_batch(&self, table: &TableName, source: &Source) -> Result<Batch>;
+    // the latest batches of the table, newest first
+    fn batches(&self, table: &TableName, limit: usize) -> Result<Vec<Batch>>;
+    // takes back everything the batch added, None if the table has no such
+    // batch or it's undone already
+    fn undo(&self, table: &TableName, batch: i64) -> Result<Option<Forgotten>>;
+
+    fn get_all_users(&self) -> Result<HashMap<String, UserAccount>>;
+    fn insert_user(&self, user: &UserAccount) -> Result<()>;
+    // everything but admin rights, they change only by set_admin
+    fn update_user(&self, user: &UserAccount) -> Result<()>;
+    fn set_admin(&self, user_id: &str, is_admin: bool) -> Result<()>;
+
+    fn insert(&self, batch: &Batch, text: &str, user: &str) -> Result<()> {
+        let order = self.table_order(&batch.table)?;
+        let ngrams = split_ngrams(text, order);
+
+        if ngrams.is_empty() {
+            trace!("Text is too short for a table of order {}", order);
+            return Ok(());
+        }
+
+        self.insert_ngrams(batch, &ngrams, user)
+    }
+
+    // Every text is a message of its own, they go in by chunks, all into the
+    // same batch. Returns how many non-empty texts and n-grams were learned.
+    fn insert_many(&self, batch: &Batch, texts: &[&str], user: &str) -> Result<(usize, usize)> {
+        let order = self.table_order(&batch.table)?;
+        let (mut learned, mut inserted) = (0, 0);
+        let mut ngrams = Vec::with_capacity(INSERTED_NGRAMS_CHUNK);
+
+        for text in texts.iter().filter(|text| !text.trim().is_empty()) {
+            learned += 1;
+            ngrams.extend(split_ngrams(text, order));
+            if ngrams.len() >= INSERTED_NGRAMS_CHUNK {
+                self.insert_ngrams(batch, &ngrams, user)?;
+                inserted += ngrams.len();
+                ngrams.clear();
+            }
+        }
+
+        if !ngrams.is_empty() {
+            self.insert_ngrams(batch, &ngrams, user)?;
+            inserted += ngrams.len();
+        }
+        Ok((learned, inserted))
+    }
+}
@@ -1,24 +1,45 @@
+use crate::document;
+use crate::error::{Error, Result};
 use futures::StreamExt;
+use serde_json::json;
 use telegram_bot::*;
 use log::{debug, trace, info, error, warn};
-use std::path::Path;
-use std::ffi::OsStr;
+use std::time::Duration;
 
 macro_rules! make_reply {
     ($e:expr) => (TelegramActions::ReplyToMessage(String::from($e)));
 }
 
-const FILE_SIZE_LIMIT_BYTES: i64 = 10_000_000; // 10 MiB
+// bots can't download bigger files anyway, large texts should come compressed
+const FILE_SIZE_LIMIT_BYTES: i64 = 20_000_000; // 20 MB
+pub const MESSAGE_LIMIT_CHARS: usize = 4096; // telegram refuses longer messages
+// a bot which is blocked or kicked out of the chat would never get through
+const SEND_ATTEMPTS: u32 = 3;
+const SEND_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 pub struct Telegram {
     api: Api,
     token: String
 }
 
-#[derive(Debug)]
-enum TelegramErrors {
-    FileSizeIsTooBig,
-    FileExtensionMissingOrWrong,
+// a text message as the handler sees it
+pub struct IncomingMessage {
+    pub chat_id: ChatId,
+    pub user_id: UserId,
+    // author of the message this one replies to
+    pub reply_to: Option<UserId>,
+    pub text: String,
+}
+
+// a downloaded document, the caption may name a table to learn it into
+pub struct IncomingDocument {
+    pub chat_id: ChatId,
+    pub user_id: UserId,
+    pub caption: Option<String>,
+    // as telegram keeps it, only the extension is of any use
+    pub file_name: String,
+    // as it was uploaded, maybe compressed
+    pub data: Vec<u8>,
 }
 
 pub enum TelegramActions {
@@ -34,110 +55,177 @@ impl Telegram {
         Telegram { api, token: String::from(token) }
     }
 
+    // the bot itself, it ignores commands addressed to other bots and
+    // recognizes replies to its own messages
+    pub async fn me(&self) -> Result<User> {
+        let me = self.api.send(GetMe).await?;
+        info!("Logged in as {:?}", me.username);
+        Ok(me)
+    }
+
+    // the menu telegram clients show for "/", telegram-bot has no request for it
+    pub async fn set_commands(&self, commands: &[(String, String)]) -> Result<()> {
+        let commands = commands
+            .iter()
+            .map(|(command, description)| json!({ "command": command, "description": description }))
+            .collect::<Vec<_>>();
+        let url = format!("https://api.telegram.org/bot{}/setMyCommands", self.token);
+
+        reqwest::Client::new()
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json!({ "commands": commands }).to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        info!("Set {} commands for the menu", commands.len());
+        Ok(())
+    }
+
     async fn send_reply(api: Api, message: &Message, text: String) {
         trace!("Sending a reply");
-        while let Err(e) = api.send(message.text_reply(&text)).await {
-            error!("Error in api.send_reply() - {}", e);
+        for attempt in 1..=SEND_ATTEMPTS {
+            match api.send(message.text_reply(&text)).await {
+                Ok(_) => return,
+                Err(e) => error!("Error in api.send_reply(), attempt {} of {} - {}", attempt, SEND_ATTEMPTS, e),
+            }
+            if attempt < SEND_ATTEMPTS {
+                tokio::time::delay_for(SEND_RETRY_DELAY).await;
+            }
         }
+        warn!("Gave up replying in chat {}", message.chat.id());
     }
 
     async fn send_to_chat(api: Api, message: &Message, text: String) {
         trace!("Sending to a chat");
-        while let Err(e) = api.send(message.chat.text(&text)).await {
-            error!("Error in api.send_to_chat() - {}", e);
+        for attempt in 1..=SEND_ATTEMPTS {
+            match api.send(message.chat.text(&text)).await {
+                Ok(_) => return,
+                Err(e) => error!("Error in api.send_to_chat(), attempt {} of {} - {}", attempt, SEND_ATTEMPTS, e),
+            }
+            if attempt < SEND_ATTEMPTS {
+                tokio::time::delay_for(SEND_RETRY_DELAY).await;
+            }
         }
+        warn!("Gave up sending to chat {}", message.chat.id());
     }
 
     async fn send_message(api: Api, message: &Message, action: TelegramActions) {
+        // a too long text would be refused and resent forever
+        let fit = |s: String| s.chars().take(MESSAGE_LIMIT_CHARS).collect::<String>();
         match action {
-            TelegramActions::ReplyToMessage(s) => Telegram::send_reply(api, message, s).await,
-            TelegramActions::ReplyToChat(s) => Telegram::send_to_chat(api, message, s).await,
+            TelegramActions::ReplyToMessage(s) => Telegram::send_reply(api, message, fit(s)).await,
+            TelegramActions::ReplyToChat(s) => Telegram::send_to_chat(api, message, fit(s)).await,
             TelegramActions::NoReply => { trace!("No reply to this command"); }
         };
     }
 
-    fn get_extension_from_filename(filename: &str) -> Option<&str> {
-        Path::new(filename)
-            .extension()
-            .and_then(OsStr::to_str)
-    }
-
-    async fn validate_and_get_document_url(token: String, api: &Api, document: Document) -> Result<String, TelegramErrors> {
-        let link = api.send(GetFile::new(&document)).await.unwrap();
-        info!("filesize {}", link.file_size.unwrap());
+    // the file name and the link to download it
+    async fn validate_and_get_document_url(token: String, api: &Api, document: Document) -> Result<(String, String)> {
+        let link = api.send(GetFile::new(&document)).await?;
+        info!("filesize {:?}", link.file_size);
         if link.file_size.unwrap_or(FILE_SIZE_LIMIT_BYTES) >= FILE_SIZE_LIMIT_BYTES {
-            return Err(TelegramErrors::FileSizeIsTooBig)
+            return Err(Error::FileSizeIsTooBig)
         }
-        let file_name = link.file_path.unwrap();
-        if !Telegram::get_extension_from_filename(&file_name).map_or(false, |ext| ext == "txt") {
-            return Err(TelegramErrors::FileExtensionMissingOrWrong)
+        let file_name = link.file_path.ok_or(Error::FilePathMissing)?;
+        if !document::is_supported(&file_name) {
+            return Err(Error::FileExtensionMissingOrWrong)
         }
         let url = format!("https://api.telegram.org/file/bot{}/{}", token, file_name);
-        Ok(url)
+        Ok((file_name, url))
     }
 
-    async fn download_document_from_url(url: String) -> String {
-        let response = reqwest::get(&url)
-            .await.unwrap();
+    // the size telegram told may be missing, so it's checked once more while downloading
+    async fn download_document_from_url(url: String) -> Result<Vec<u8>> {
+        let mut response = reqwest::get(&url)
+            .await?
+            .error_for_status()?;
 
         for (key, value) in response.headers().iter() {
-            println!("HEADERS {:?}: {:?}", key, value);
+            trace!("HEADERS {:?}: {:?}", key, value);
         };
 
-        let body = response.text()
-            .await.unwrap();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() as i64 >= FILE_SIZE_LIMIT_BYTES {
+                return Err(Error::FileSizeIsTooBig)
+            }
+        }
+
+        trace!("body of {} bytes", body.len());
+        Ok(body)
+    }
 
-        println!("body = {:?}", body);
-        body
+    async fn fetch_document(token: String, api: &Api, message: &Message, document: Document) -> Result<(String, Vec<u8>)> {
+        let (file_name, url) = Telegram::validate_and_get_document_url(token, api, document).await?;
+        Telegram::send_message(api.clone(), message, make_reply!("File is in progress")).await;
+        info!("document {}", file_name);
+        Ok((file_name, Telegram::download_document_from_url(url).await?))
     }
 
-    pub async fn serve<F, P>(&self, message_handler: F, file_handler: P) -> ()
+    // documents of users `may_upload` refuses are ignored before anything is
+    // asked of telegram or downloaded, and nothing is sent back to them
+    pub async fn serve<F, P, U>(&self, message_handler: F, file_handler: P, may_upload: U) -> ()
     where
-        F: Fn(ChatId, String) -> TelegramActions,
+        F: Fn(IncomingMessage) -> TelegramActions,
         F: Copy + Send + 'static,
-        P: Fn(ChatId, String),
+        P: Fn(IncomingDocument) -> TelegramActions,
         P: Copy + Send + 'static,
+        U: Fn(UserId) -> bool,
     {
         let mut stream = self.api.stream();
         while let Some(update) = stream.next().await {
-            if update.is_err() {
-                continue
-            }
-            let update = update.unwrap();
+            let update = match update {
+                Ok(update) => update,
+                Err(e) => {
+                    warn!("Error in getting updates: {}", e);
+                    continue
+                }
+            };
             if let UpdateKind::Message(message) = update.kind {
                  match message.kind {
                      MessageKind::Text { ref data, .. } => {
                          let api = self.api.clone();
-                         let data = data.clone();
-                         let chat_id = message.chat.id();
+                         let incoming = IncomingMessage {
+                             chat_id: message.chat.id(),
+                             user_id: message.from.id,
+                             reply_to: match message.reply_to_message.as_deref() {
+                                 Some(MessageOrChannelPost::Message(replied)) => Some(replied.from.id),
+                                 _ => None,
+                             },
+                             text: data.clone(),
+                         };
                          tokio::spawn(async move {
-                             info!("<{}>: {}", &message.from.first_name, data);
+                             info!("<{}>: {}", &message.from.first_name, incoming.text);
                              let action = tokio::task::block_in_place(move || {
-                                 message_handler(chat_id, data)
+                                 message_handler(incoming)
                              });
                              Telegram::send_message(api, &message, action).await;
                          });
                      },
-                     MessageKind::Document { ref data, .. } => {
-                         // save the document, parse it as .txt file and push data into sqlite
+                     MessageKind::Document { .. } if !may_upload(message.from.id) => {
+                         info!("User {} may not upload files, the file is ignored", message.from.id);
+                     },
+                     MessageKind::Document { ref data, ref caption } => {
+                         // download the document, reading and learning it is up to the file handler
                          let api = self.api.clone();
                          let token = self.token.clone();
                          let document = data.clone();
                          let chat_id = message.chat.id();
+                         let user_id = message.from.id;
+                         let caption = caption.clone();
                          tokio::spawn(async move {
-                             let doc = Telegram::validate_and_get_document_url(token, &api, document).await;
-                             match doc {
-                                 Ok(url) => {
-                                     Telegram::send_message(api, &message, make_reply!("File is in progress")).await;
-                                     info!("document {}", url);
-                                     let file_body = Telegram::download_document_from_url(url).await;
-                                     file_handler(chat_id, file_body);
-                                 },
+                             let action = match Telegram::fetch_document(token, &api, &message, document).await {
+                                 Ok((file_name, data)) => tokio::task::block_in_place(move || {
+                                     file_handler(IncomingDocument { chat_id, user_id, caption, file_name, data })
+                                 }),
                                  Err(e) => {
-                                     warn!("Error in file validation: {:?}", e);
-                                     Telegram::send_message(api, &message, make_reply!("Some problems with file, maybe it's too big, or it's not a txt file")).await;
+                                     warn!("Error in getting the file: {}", e);
+                                     make_reply!(e.reply())
                                  }
                              };
+                             Telegram::send_message(api, &message, action).await;
                          });
                      },
                      typ => {
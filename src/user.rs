@@ -1,7 +1,28 @@
+use crate::generator::Limits;
+use crate::store::SourceFilter;
+
 #[derive(Debug, Clone)]
 pub struct UserAccount {
     pub user_id: String,
     pub is_admin: bool,
     pub answer_mode: bool,
+    pub learn_mode: bool,
     pub lexeme_table: String,
+    pub temperature: f64,
+    // chance to answer a message nobody addressed to the bot
+    pub reply_probability: f64,
+    // answer only mentions, replies to the bot and triggers
+    pub mention_only: bool,
+    // lowercase words which always get an answer
+    pub triggers: Vec<String>,
+    // seconds to stay silent after an answer
+    pub cooldown: i64,
+    // answers are built around a word of the message instead of being random
+    pub seeded_replies: bool,
+    // length of generated answers
+    pub limits: Limits,
+    // words in a row an answer may take from one message, zero is any
+    pub max_copied: usize,
+    // answers are made only of n-grams of these sources
+    pub source: SourceFilter,
 }
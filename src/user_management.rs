@@ -1,56 +1,114 @@
+use crate::error::Result;
 use crate::user::*;
-use crate::sqlite::*;
+use crate::generator::{Limits, DEFAULT_MAXIMUM_COPIED, DEFAULT_TEMPERATURE};
+use crate::store::*;
 use std::collections::HashMap;
 use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_REPLY_PROBABILITY: f64 = 1.0;
 
 pub struct UserManager {
     user_table: Arc<Mutex<HashMap<String, UserAccount>>>,
+    // when every chat got its last answer, nobody cares after a restart
+    last_answers: Mutex<HashMap<String, Instant>>,
 }
 
 impl UserManager {
-    pub fn new(conn: &mut SqliteConn) -> UserManager
+    pub fn new(conn: &dyn LexemeStore) -> Result<UserManager>
     {
-        let users = conn.get_all_users();
-        UserManager { user_table: Arc::new(Mutex::new(users)) }
+        let users = conn.get_all_users()?;
+        Ok(UserManager { user_table: Arc::new(Mutex::new(users)), last_answers: Mutex::new(HashMap::new()) })
     }
 
-    pub fn get_user(&self, conn: &SqliteConn, user_id: &str) -> UserAccount {
+    pub fn get_user(&self, conn: &dyn LexemeStore, user_id: &str) -> Result<UserAccount> {
         UserManager::get_or_insert(Arc::clone(&self.user_table), conn, user_id)
     }
 
-    pub fn update_user(&self, conn: &SqliteConn, user: &UserAccount) {
+    pub fn update_user(&self, conn: &dyn LexemeStore, user: &UserAccount) -> Result<()> {
         UserManager::update_or_ignore(Arc::clone(&self.user_table), conn, user)
     }
 
-    fn insert_account(map: &mut HashMap<String, UserAccount>, conn: &SqliteConn, user_id: &str) {
+    // only profiles already known are looked at, nobody is created here
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        let hash_table = self.user_table.lock().unwrap();
+        hash_table.get(user_id).map_or(false, |user| user.is_admin)
+    }
+
+    // the only way admin rights change, settings of a profile never touch them
+    pub fn set_admin(&self, conn: &dyn LexemeStore, user_id: &str, is_admin: bool) -> Result<()> {
+        self.get_user(conn, user_id)?;
+        let mut hash_table = self.user_table.lock().unwrap();
+
+        conn.set_admin(user_id, is_admin)?;
+        if let Some(user) = hash_table.get_mut(user_id) {
+            user.is_admin = is_admin;
+        }
+        Ok(())
+    }
+
+    // true if the cooldown of the chat is over, the answer is counted right away
+    pub fn try_answer(&self, user: &UserAccount) -> bool {
+        let mut last_answers = self.last_answers.lock().unwrap();
+        let cooldown = Duration::from_secs(user.cooldown.max(0) as u64);
+        let now = Instant::now();
+
+        match last_answers.get(&user.user_id) {
+            Some(last) if now.duration_since(*last) < cooldown => false,
+            _ => {
+                last_answers.insert(user.user_id.clone(), now);
+                true
+            }
+        }
+    }
+
+    fn insert_account(map: &mut HashMap<String, UserAccount>, conn: &dyn LexemeStore, user_id: &str) -> Result<()> {
         let user_account = UserAccount { 
             user_id: String::from(user_id), 
             is_admin: false, 
             answer_mode: true, 
-            lexeme_table: String::from(super::sqlite::DEFAULT_TABLE) 
+            learn_mode: true,
+            lexeme_table: String::from(DEFAULT_TABLE),
+            temperature: DEFAULT_TEMPERATURE,
+            reply_probability: DEFAULT_REPLY_PROBABILITY,
+            mention_only: false,
+            triggers: Vec::new(),
+            cooldown: 0,
+            seeded_replies: true,
+            limits: Limits::default(),
+            max_copied: DEFAULT_MAXIMUM_COPIED,
+            source: SourceFilter::any(),
         };
 
-        conn.insert_user(&user_account);
+        // the cache follows the database, so a failed insert is retried next time
+        conn.insert_user(&user_account)?;
         map.insert(String::from(user_id), user_account);
+        Ok(())
     }
 
-    fn get_or_insert(table: Arc<Mutex<HashMap<String, UserAccount>>>, conn: &SqliteConn, user_id: &str) -> UserAccount {
+    fn get_or_insert(table: Arc<Mutex<HashMap<String, UserAccount>>>, conn: &dyn LexemeStore, user_id: &str) -> Result<UserAccount> {
         let locked_table = &table;
         let mut hash_table = locked_table.lock().unwrap();
 
         if !hash_table.contains_key(user_id) {
-            UserManager::insert_account(&mut hash_table, conn, user_id);
+            UserManager::insert_account(&mut hash_table, conn, user_id)?;
         }
 
         // previously we inserted it in sqlite and in hashmap
-        hash_table.get(user_id).unwrap().clone()
+        Ok(hash_table.get(user_id).unwrap().clone())
     }
 
-    fn update_or_ignore(table: Arc<Mutex<HashMap<String, UserAccount>>>, conn: &SqliteConn, user: &UserAccount) {
+    fn update_or_ignore(table: Arc<Mutex<HashMap<String, UserAccount>>>, conn: &dyn LexemeStore, user: &UserAccount) -> Result<()> {
         let locked_table = &table;
         let mut hash_table = locked_table.lock().unwrap();
 
-        hash_table.insert(user.user_id.clone(), user.clone());
-        conn.update_user(user);
+        // the profile may have been read before its admin rights changed
+        let mut user = user.clone();
+        if let Some(cached) = hash_table.get(&user.user_id) {
+            user.is_admin = cached.is_admin;
+        }
+        conn.update_user(&user)?;
+        hash_table.insert(user.user_id.clone(), user);
+        Ok(())
     }
 }